@@ -1,226 +1,1066 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    currency::Currency,
+    error::TransactionError,
+    fx::FxRates,
+    money::Money,
+    policy::{NegativeBalances, Policy, WithdrawalDisputes},
+    record::{ClientId, Record, RecordType, Timestamp, TransactionId},
+};
+
+/// Lifecycle of a processed deposit or withdrawal. The only legal transitions are
+/// `Processed -> Disputed` and `Disputed -> Resolved | ChargedBack`; once its dispute is settled
+/// a transaction can only be disputed again for the part of its amount that was never disputed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionState {
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl TransactionState {
+    /// Whether a transaction in this state may move to `next`.
+    pub fn can_become(self, next: TransactionState) -> bool {
+        matches!(
+            (self, next),
+            (TransactionState::Processed, TransactionState::Disputed)
+                | (TransactionState::Disputed, TransactionState::Resolved)
+                | (TransactionState::Disputed, TransactionState::ChargedBack)
+        )
+    }
+}
+
+impl std::fmt::Display for TransactionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionState::Processed => write!(f, "processed"),
+            TransactionState::Disputed => write!(f, "disputed"),
+            TransactionState::Resolved => write!(f, "resolved"),
+            TransactionState::ChargedBack => write!(f, "charged back"),
+        }
+    }
+}
+
+// a single dispute of a transaction, several can be open at once
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Dispute {
+    amount: Money,
+    // the part of the amount that is held, less than the amount when the hold was capped
+    held: Money,
+    // when the dispute is resolved unless settled earlier
+    deadline: Option<Timestamp>,
+}
 
-use crate::record::{Record, RecordType};
+// a deposit, withdrawal or incoming transfer that was applied to the account
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Transaction {
+    record_type: RecordType,
+    currency: Option<Currency>,
+    amount: Money,
+    timestamp: Option<Timestamp>,
+    // disputed while any dispute is open, otherwise how the latest dispute was settled
+    state: TransactionState,
+    // the part of the amount that was charged back, it can't be disputed again
+    charged_back: Money,
+    // the part of the amount that was resolved, it can't be disputed again either
+    resolved: Money,
+    // open disputes, oldest first
+    disputes: Vec<Dispute>,
+}
+
+impl Transaction {
+    // the part of the amount under an open dispute; disputes never exceed the amount, so the
+    // sum always fits
+    fn under_dispute(&self) -> Money {
+        self.disputes.iter().fold(Money::ZERO, |sum, dispute| {
+            sum.checked_add(dispute.amount)
+                .expect("disputed amount overflowed")
+        })
+    }
 
+    // the part of the amount that was never disputed - neither under an open dispute, nor
+    // resolved, nor charged back
+    fn undisputed(&self) -> Option<Money> {
+        self.amount
+            .checked_sub(self.charged_back)?
+            .checked_sub(self.resolved)?
+            .checked_sub(self.under_dispute())
+    }
+}
+
+// the funds of an account in a single currency
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct Balance {
+    available: Money,
+    held: Money,
+    receivable: Money,
+}
+
+impl Balance {
+    fn total(&self) -> Money {
+        // deposit refuses amounts that would overflow the total, so this always fits
+        self.available
+            .checked_add(self.held)
+            .expect("total of available and held funds overflowed")
+    }
+}
+
+// the amount of a record that moves funds, which has to be there and be positive - a negative
+// amount would move the funds the other way
+pub(crate) fn positive_amount(record: &Record) -> Result<Money, TransactionError> {
+    let log_header = "account::positive_amount";
+    let amount = record.amount.ok_or_else(|| {
+        log::debug!("{}: amount in record is None, skipping", log_header);
+        TransactionError::MissingAmount
+    })?;
+    if amount <= Money::ZERO {
+        log::debug!(
+            "{}: amount is not positive, record == {}",
+            log_header,
+            record
+        );
+        return Err(TransactionError::InvalidAmount);
+    }
+    Ok(amount)
+}
+
+/// A single client account - balances per currency, lock flag and the history needed to
+/// settle disputes. Records without a currency use a balance of their own.
 pub struct Account {
-    id: u16,
-    available: f64,
-    held: f64,
+    id: ClientId,
+    balances: BTreeMap<Option<Currency>, Balance>,
+    locked: bool,
+    closed: bool,
+    transactions: HashMap<TransactionId, Transaction>,
+    policy: Policy,
+}
+
+// an account as kept in an engine snapshot, everything but the policy of the run
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AccountState {
+    id: ClientId,
+    balances: Vec<(Option<Currency>, Balance)>,
     locked: bool,
-    transactions: HashMap<u16, Record>,
-    disputes: HashMap<u16, Record>,
+    closed: bool,
+    // sorted by id, so the same state is always written the same way
+    transactions: Vec<(TransactionId, Transaction)>,
+}
+
+/// A point-in-time copy of the balances of an `Account` in a single currency.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct AccountSnapshot {
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    /// `None` for the balance of records without a currency.
+    pub currency: Option<Currency>,
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    pub locked: bool,
+    /// Funds the client owes because a dispute hold was capped at the available funds.
+    pub receivable: Money,
+    /// Whether the available or total funds are negative.
+    pub overdrawn: bool,
 }
 
-impl std::fmt::Display for Account {
+impl std::fmt::Display for AccountSnapshot {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{}, {:.4}, {:.4}, {:.4}, {}",
-            self.id,
+            "{}, {}, {}, {}, {}, {}, {}, {}",
+            self.client_id,
+            self.currency
+                .map(|currency| currency.to_string())
+                .unwrap_or_default(),
             self.available,
             self.held,
-            self.total(),
-            self.locked
+            self.total,
+            self.locked,
+            self.receivable,
+            self.overdrawn
         )
     }
 }
 
 impl Account {
-    pub fn new(id: u16) -> Self {
+    /// Creates an empty, unlocked account for the given client with the default `Policy`.
+    pub fn new(id: ClientId) -> Self {
+        Self::with_policy(id, Policy::default())
+    }
+
+    /// Creates an empty, unlocked account that settles disputes by the given `Policy`.
+    pub fn with_policy(id: ClientId, policy: Policy) -> Self {
         Self {
             id,
-            available: 0.,
-            held: 0.,
+            balances: BTreeMap::<Option<Currency>, Balance>::new(),
             locked: false,
-            transactions: HashMap::<u16, Record>::new(),
-            disputes: HashMap::<u16, Record>::new(),
+            closed: false,
+            transactions: HashMap::<TransactionId, Transaction>::new(),
+            policy,
         }
     }
 
-    fn total(&self) -> f64 {
-        self.available + self.held
+    /// The client the account belongs to.
+    pub fn id(&self) -> ClientId {
+        self.id
     }
 
-    pub fn process(&mut self, record: Record) {
-        let log_header = "Account::process";
-        if self.locked {
-            log::debug!("{}: Account is locked, record == {}", log_header, &record);
-            return;
+    // the state to keep in an engine snapshot
+    pub(crate) fn state(&self) -> AccountState {
+        let mut transactions: Vec<(TransactionId, Transaction)> = self
+            .transactions
+            .iter()
+            .map(|(trx_id, transaction)| (*trx_id, transaction.clone()))
+            .collect();
+        transactions.sort_unstable_by_key(|(trx_id, _)| *trx_id);
+        AccountState {
+            id: self.id,
+            balances: self
+                .balances
+                .iter()
+                .map(|(currency, balance)| (*currency, *balance))
+                .collect(),
+            locked: self.locked,
+            closed: self.closed,
+            transactions,
         }
+    }
+
+    // an account restored from a snapshot, settling disputes by `policy`
+    pub(crate) fn from_state(state: AccountState, policy: Policy) -> Self {
+        Self {
+            id: state.id,
+            balances: state.balances.into_iter().collect(),
+            locked: state.locked,
+            closed: state.closed,
+            transactions: state.transactions.into_iter().collect(),
+            policy,
+        }
+    }
+
+    // every open dispute that ends by itself, with its transaction and deadline
+    pub(crate) fn dispute_deadlines(
+        &self,
+    ) -> impl Iterator<Item = (TransactionId, Timestamp)> + '_ {
+        self.transactions.iter().flat_map(|(trx_id, transaction)| {
+            transaction
+                .disputes
+                .iter()
+                .filter_map(|dispute| dispute.deadline.map(|deadline| (*trx_id, deadline)))
+        })
+    }
 
-        if !self.process_record(&record) {
+    /// Returns a copy of the current balance in the given currency.
+    pub fn snapshot(&self, currency: Option<Currency>) -> AccountSnapshot {
+        self.snapshot_of(currency, self.balance(currency))
+    }
+
+    fn snapshot_of(&self, currency: Option<Currency>, balance: Balance) -> AccountSnapshot {
+        let total = balance.total();
+        AccountSnapshot {
+            client_id: self.id,
+            currency,
+            available: balance.available,
+            held: balance.held,
+            total,
+            locked: self.locked,
+            receivable: balance.receivable,
+            overdrawn: balance.available.is_negative() || total.is_negative(),
+        }
+    }
+
+    /// Returns a copy of the balance in every currency the account has used, ordered by
+    /// currency. An account that never had funds has a single empty balance without a currency.
+    pub fn snapshots(&self) -> Vec<AccountSnapshot> {
+        if self.balances.is_empty() {
+            return vec![self.snapshot(None)];
+        }
+        self.balances
+            .keys()
+            .map(|currency| self.snapshot(*currency))
+            .collect()
+    }
+
+    /// Returns the balances in every currency converted to `currency` with the latest rates and
+    /// added up. Returns `None` when a balance has no rate to `currency`, or has funds but no
+    /// currency at all.
+    pub fn consolidated(&self, currency: Currency, rates: &FxRates) -> Option<AccountSnapshot> {
+        let convert = |amount: Money, from: Option<Currency>| {
+            if amount == Money::ZERO {
+                return Some(Money::ZERO);
+            }
+            rates.convert(amount, from?, currency, None)
+        };
+
+        let mut consolidated = Balance::default();
+        for (from, balance) in &self.balances {
+            consolidated = Balance {
+                available: consolidated
+                    .available
+                    .checked_add(convert(balance.available, *from)?)?,
+                held: consolidated
+                    .held
+                    .checked_add(convert(balance.held, *from)?)?,
+                receivable: consolidated
+                    .receivable
+                    .checked_add(convert(balance.receivable, *from)?)?,
+            };
+        }
+        consolidated.available.checked_add(consolidated.held)?;
+        Some(self.snapshot_of(Some(currency), consolidated))
+    }
+
+    fn balance(&self, currency: Option<Currency>) -> Balance {
+        self.balances.get(&currency).copied().unwrap_or_default()
+    }
+
+    fn set_balance(&mut self, currency: Option<Currency>, balance: Balance, log_header: &str) {
+        log::debug!(
+            "{}: {} balance, available == {}, held == {}, receivable == {}",
+            log_header,
+            currency.as_ref().map_or("default", Currency::as_str),
+            balance.available,
+            balance.held,
+            balance.receivable
+        );
+        self.balances.insert(currency, balance);
+    }
+
+    /// Returns the dispute state of a deposit or withdrawal of this account.
+    pub fn transaction_state(&self, trx_id: TransactionId) -> Option<TransactionState> {
+        self.transactions
+            .get(&trx_id)
+            .map(|transaction| transaction.state)
+    }
+
+    /// Returns when the latest open dispute of a deposit or withdrawal is resolved by itself,
+    /// if ever.
+    pub fn dispute_deadline(&self, trx_id: TransactionId) -> Option<Timestamp> {
+        self.transactions
+            .get(&trx_id)
+            .and_then(|transaction| transaction.disputes.last())
+            .and_then(|dispute| dispute.deadline)
+    }
+
+    /// Resolves an open dispute of a deposit or withdrawal whose hold period ended at
+    /// `deadline`; when no open dispute has this deadline, because it was settled in the
+    /// meantime, nothing changes. Returns whether a dispute was resolved.
+    ///
+    /// Expiry is not a client operation, so it also applies to a locked account, but never
+    /// to a closed one.
+    pub fn expire_dispute(&mut self, trx_id: TransactionId, deadline: Timestamp) -> bool {
+        let log_header = "Account::expire_dispute";
+        if self.closed {
+            return false;
+        }
+        let Some((disputed, index)) = self.transactions.get(&trx_id).and_then(|transaction| {
+            transaction
+                .disputes
+                .iter()
+                .position(|dispute| dispute.deadline == Some(deadline))
+                .map(|index| (transaction.clone(), index))
+        }) else {
+            return false;
+        };
+
+        let record = Record {
+            amount: Some(disputed.disputes[index].amount),
+            timestamp: Some(deadline),
+            ..Record::new(RecordType::Resolve, self.id, trx_id)
+        };
+        match self.resolve_dispute(&record, &disputed, index, log_header) {
+            Ok(()) => {
+                log::debug!("{}: dispute expired, record == {}", log_header, record);
+                true
+            }
+            Err(error) => {
+                log::warn!(
+                    "{}: expired dispute could not be resolved, error == {}, record == {}",
+                    log_header,
+                    error,
+                    record
+                );
+                false
+            }
+        }
+    }
+
+    /// Returns the part of a deposit or withdrawal that is under an open dispute.
+    pub fn under_dispute(&self, trx_id: TransactionId) -> Option<Money> {
+        self.transactions
+            .get(&trx_id)
+            .map(Transaction::under_dispute)
+    }
+
+    /// Returns the amount of the open dispute a resolve or chargeback record would settle.
+    pub fn settled_amount(&self, record: &Record) -> Option<Money> {
+        let transaction = self.transactions.get(&record.trx_id)?;
+        Self::settled_dispute(record, transaction, "Account::settled_amount")
+            .ok()
+            .map(|index| transaction.disputes[index].amount)
+    }
+
+    /// Applies a record to this account; a refused record leaves the account untouched
+    /// and the error says why it was refused.
+    pub fn process(&mut self, record: &Record) -> Result<(), TransactionError> {
+        let log_header = "Account::process";
+        self.check_open(record, log_header)?;
+
+        if let Err(error) = self.process_record(record) {
             log::debug!(
-                "{}: record could not've been processed, record == {}",
+                "{}: record could not've been processed, error == {}, record == {}",
                 log_header,
-                &record
+                error,
+                record
             );
-            return;
+            return Err(error);
         }
 
-        let collection = if record.record_type == RecordType::Dispute
-            || record.record_type == RecordType::Resolve
-        {
-            &mut self.disputes
-        } else {
-            &mut self.transactions
-        };
         log::debug!(
-            "{}: Record has been processed - inserting into collection, record == {}",
+            "{}: Record has been processed, record == {}",
             log_header,
-            &record
+            record
         );
-        collection.insert(record.trx_id, record);
+        Ok(())
+    }
+
+    /// Applies a conversion record that moves `record.amount` out of the `currency` balance and
+    /// `converted` into the `to_currency` balance.
+    pub fn convert(&mut self, record: &Record, converted: Money) -> Result<(), TransactionError> {
+        let log_header = "Account::convert";
+        self.check_open(record, log_header)?;
+
+        let (from, to) = match (record.currency, record.to_currency) {
+            (Some(from), Some(to)) => (Some(from), Some(to)),
+            _ => return Err(TransactionError::MissingCurrency),
+        };
+        if from == to {
+            log::debug!(
+                "{}: converts into its own currency, record == {}",
+                log_header,
+                record
+            );
+            return Err(TransactionError::SameCurrency);
+        }
+        let amount = positive_amount(record)?;
+
+        let source = self.balance(from);
+        let target = self.balance(to);
+        let available = source
+            .available
+            .checked_sub(amount)
+            .ok_or(TransactionError::Overflow)?;
+        if available.is_negative() {
+            log::debug!(
+                "{}: available is smaller then supplied amount, available == {}, amount == {}",
+                log_header,
+                source.available,
+                amount
+            );
+            return Err(TransactionError::InsufficientFunds);
+        }
+        let converted_available = match target.available.checked_add(converted) {
+            Some(converted_available) if target.total().checked_add(converted).is_some() => {
+                converted_available
+            }
+            _ => {
+                log::warn!(
+                    "{}: conversion would overflow the balance, available == {}, converted == {}",
+                    log_header,
+                    target.available,
+                    converted
+                );
+                return Err(TransactionError::Overflow);
+            }
+        };
+
+        self.set_balance(
+            from,
+            Balance {
+                available,
+                ..source
+            },
+            log_header,
+        );
+        self.set_balance(
+            to,
+            Balance {
+                available: converted_available,
+                ..target
+            },
+            log_header,
+        );
+        Ok(())
+    }
+
+    /// Checks that an incoming transfer would be applied, without applying it - a transfer is
+    /// only taken out of the source account once the destination is known to accept it.
+    pub fn check_transfer_in(&self, record: &Record) -> Result<(), TransactionError> {
+        let log_header = "Account::check_transfer_in";
+        self.check_open(record, log_header)?;
+        self.credited(record, log_header).map(|_| ())
+    }
+
+    /// Checks that a charged back transfer could be refunded to this account, without
+    /// refunding it.
+    pub fn check_refund(
+        &self,
+        currency: Option<Currency>,
+        amount: Money,
+    ) -> Result<(), TransactionError> {
+        self.refunded(currency, amount, "Account::check_refund")
+            .map(|_| ())
+    }
+
+    /// Credits back `amount` of a transfer charged back at the destination. A refund is not a
+    /// client operation, so it also applies to a locked account, but never to a closed one.
+    pub fn refund(
+        &mut self,
+        currency: Option<Currency>,
+        amount: Money,
+    ) -> Result<(), TransactionError> {
+        let log_header = "Account::refund";
+        let balance = self.refunded(currency, amount, log_header)?;
+        self.set_balance(currency, balance, log_header);
+        Ok(())
+    }
+
+    fn refunded(
+        &self,
+        currency: Option<Currency>,
+        amount: Money,
+        log_header: &str,
+    ) -> Result<Balance, TransactionError> {
+        if self.closed {
+            log::debug!("{}: Account is closed, amount == {}", log_header, amount);
+            return Err(TransactionError::AccountClosed);
+        }
+
+        let balance = self.balance(currency);
+        match balance.available.checked_add(amount) {
+            Some(available) if balance.total().checked_add(amount).is_some() => Ok(Balance {
+                available,
+                ..balance
+            }),
+            _ => {
+                log::warn!(
+                    "{}: refund would overflow the balance, available == {}, amount == {}",
+                    log_header,
+                    balance.available,
+                    amount
+                );
+                Err(TransactionError::Overflow)
+            }
+        }
+    }
+
+    // a closed account takes no records, a locked one only administrative records
+    fn check_open(&self, record: &Record, log_header: &str) -> Result<(), TransactionError> {
+        if self.closed {
+            log::debug!("{}: Account is closed, record == {}", log_header, &record);
+            return Err(TransactionError::AccountClosed);
+        }
+
+        if self.locked && !record.record_type.is_admin() {
+            log::debug!("{}: Account is locked, record == {}", log_header, &record);
+            return Err(TransactionError::AccountLocked);
+        }
+        Ok(())
     }
 
-    fn process_record(&mut self, record: &Record) -> bool {
+    fn process_record(&mut self, record: &Record) -> Result<(), TransactionError> {
         match record.record_type {
             RecordType::Deposit => self.deposit(record),
             RecordType::Withdrawal => self.withdrawal(record),
             RecordType::Dispute => self.dispute(record),
             RecordType::Resolve => self.resolve(record),
             RecordType::Chargeback => self.chargeback(record),
-            RecordType::Invalid => false,
-            RecordType::Finished => false,
+            // a conversion needs the exchange rates of the engine, see `convert`
+            RecordType::Convert => Err(TransactionError::UnsupportedRecord(record.record_type)),
+            RecordType::Transfer if record.client_id == self.id => self.transfer_out(record),
+            // an incoming transfer is kept, and disputed, like a deposit
+            RecordType::Transfer => self.deposit(record),
+            RecordType::Unlock => self.unlock(),
+            RecordType::Freeze => self.freeze(),
+            RecordType::Close => self.close(),
         }
     }
 
-    fn deposit(&mut self, record: &Record) -> bool {
-        let log_header = "Account::deposit";
-        if record.amount.is_none() {
-            log::debug!("{}: amount in record is None, skipping", log_header);
-            return false;
+    fn new_transaction_amount(&self, record: &Record) -> Result<Money, TransactionError> {
+        let log_header = "Account::new_transaction_amount";
+        if self.transactions.contains_key(&record.trx_id) {
+            log::debug!(
+                "{}: transaction id already used, trx_id == {}",
+                log_header,
+                record.trx_id
+            );
+            return Err(TransactionError::DuplicateTransaction);
         }
 
-        let amount = record.amount.unwrap();
-        log::debug!(
-            "{}: old available == {}, new available == {}",
-            log_header,
-            self.available,
-            self.available + amount
-        );
-        self.available += amount;
-        true
+        positive_amount(record)
     }
 
-    fn withdrawal(&mut self, record: &Record) -> bool {
-        let log_header = "Account::withdrawal";
-        if record.amount.is_none() {
-            log::debug!("{}: amount in record is None, skipping", log_header);
-            return false;
+    // the amount of a deposit and the balance after it, refusing any overflow
+    fn credited(
+        &self,
+        record: &Record,
+        log_header: &str,
+    ) -> Result<(Money, Balance), TransactionError> {
+        let amount = self.new_transaction_amount(record)?;
+        let balance = self.balance(record.currency);
+        match balance.available.checked_add(amount) {
+            Some(available) if balance.total().checked_add(amount).is_some() => Ok((
+                amount,
+                Balance {
+                    available,
+                    ..balance
+                },
+            )),
+            _ => {
+                log::warn!(
+                    "{}: deposit would overflow the balance, available == {}, amount == {}",
+                    log_header,
+                    balance.available,
+                    amount
+                );
+                Err(TransactionError::Overflow)
+            }
         }
+    }
+
+    fn deposit(&mut self, record: &Record) -> Result<(), TransactionError> {
+        let log_header = "Account::deposit";
+        let (amount, balance) = self.credited(record, log_header)?;
+        self.set_balance(record.currency, balance, log_header);
+        self.insert_transaction(record, amount);
+        Ok(())
+    }
 
-        let amount = record.amount.unwrap();
-        if self.available - amount < 0. {
+    fn withdrawal(&mut self, record: &Record) -> Result<(), TransactionError> {
+        let log_header = "Account::withdrawal";
+        let amount = self.new_transaction_amount(record)?;
+        self.debit(record.currency, amount, log_header)?;
+        self.insert_transaction(record, amount);
+        Ok(())
+    }
+
+    // the transfer is kept, and disputed, by the account it was paid to
+    fn transfer_out(&mut self, record: &Record) -> Result<(), TransactionError> {
+        let log_header = "Account::transfer_out";
+        let amount = positive_amount(record)?;
+        self.debit(record.currency, amount, log_header)
+    }
+
+    // takes `amount` out of the available funds, refusing to overdraw them
+    fn debit(
+        &mut self,
+        currency: Option<Currency>,
+        amount: Money,
+        log_header: &str,
+    ) -> Result<(), TransactionError> {
+        let balance = self.balance(currency);
+        let available = balance
+            .available
+            .checked_sub(amount)
+            .ok_or(TransactionError::Overflow)?;
+        if available.is_negative() {
             log::debug!(
                 "{}: available is smaller then supplied amount, available == {}, amount == {}",
                 log_header,
-                self.available,
+                balance.available,
                 amount
             );
-            return false;
+            return Err(TransactionError::InsufficientFunds);
         }
 
-        log::debug!(
-            "{}: old available == {}, new available == {}",
+        self.set_balance(
+            currency,
+            Balance {
+                available,
+                ..balance
+            },
             log_header,
-            self.available,
-            self.available - amount
         );
-        self.available -= amount;
-        true
+        Ok(())
     }
 
-    fn dispute(&mut self, record: &Record) -> bool {
-        let log_header = "Account::dispute";
-        let deposited = self.transactions.get(&record.trx_id);
+    fn insert_transaction(&mut self, record: &Record, amount: Money) {
+        self.transactions.insert(
+            record.trx_id,
+            Transaction {
+                record_type: record.record_type,
+                currency: record.currency,
+                amount,
+                timestamp: record.timestamp,
+                state: TransactionState::Processed,
+                charged_back: Money::ZERO,
+                resolved: Money::ZERO,
+                disputes: Vec::<Dispute>::new(),
+            },
+        );
+    }
 
-        if deposited.is_none() || deposited.unwrap().record_type != RecordType::Deposit {
-            log::debug!("{}: deposited transaction not found", log_header);
-            return false;
+    // finds the transaction a dispute, resolve or chargeback refers to and checks that it may
+    // move to the `next` state
+    fn find_transaction(
+        &self,
+        record: &Record,
+        next: TransactionState,
+        log_header: &str,
+    ) -> Result<Transaction, TransactionError> {
+        let transaction = match self.transactions.get(&record.trx_id) {
+            Some(transaction) => transaction.clone(),
+            None => {
+                log::debug!("{}: referenced transaction not found", log_header);
+                return Err(TransactionError::UnknownTransaction);
+            }
+        };
+
+        if record.currency.is_some() && record.currency != transaction.currency {
+            log::debug!(
+                "{}: record currency differs from the transaction currency",
+                log_header
+            );
+            return Err(TransactionError::CurrencyMismatch);
         }
 
-        log::debug!(
-            "{}: deposited transaction found, will change available and held amounts",
-            log_header
-        );
-        let amount = deposited.unwrap().amount.unwrap();
-        log::debug!(
-            "{}: old available == {}, new available == {}",
-            log_header,
-            self.available,
-            self.available - amount
-        );
-        self.available -= amount;
-        log::debug!(
-            "{}: old held == {}, new held == {}",
-            log_header,
-            self.held,
-            self.held + amount
-        );
-        self.held += amount;
-        true
+        if next == TransactionState::Disputed
+            && !matches!(
+                transaction.record_type,
+                RecordType::Deposit | RecordType::Transfer
+            )
+            && self.policy.withdrawal_disputes == WithdrawalDisputes::Reject
+        {
+            log::debug!("{}: disputed transaction is not a deposit", log_header);
+            return Err(TransactionError::NotADeposit);
+        }
+
+        // a disputed or settled transaction may be disputed again while part of it was never
+        // disputed
+        let redispute = next == TransactionState::Disputed
+            && transaction.state != TransactionState::Processed
+            && transaction
+                .undisputed()
+                .is_some_and(|undisputed| undisputed > Money::ZERO);
+        if !(transaction.state.can_become(next) || redispute) {
+            log::debug!(
+                "{}: transaction can't go from {} to {}",
+                log_header,
+                transaction.state,
+                next
+            );
+            return Err(match transaction.state {
+                TransactionState::Processed => TransactionError::NotDisputed,
+                from => TransactionError::IllegalTransition { from, to: next },
+            });
+        }
+
+        Ok(transaction)
     }
 
-    fn resolve(&mut self, record: &Record) -> bool {
-        let log_header = "Account::resolve";
-        let disputed = self.disputes.get(&record.trx_id);
+    fn open_dispute(&mut self, record: &Record, dispute: Dispute) {
+        if let Some(transaction) = self.transactions.get_mut(&record.trx_id) {
+            transaction.state = TransactionState::Disputed;
+            transaction.disputes.push(dispute);
+        }
+    }
 
-        if disputed.is_none() || disputed.unwrap().record_type != RecordType::Dispute {
-            log::debug!("{}: disputed transaction not found", log_header);
-            return false;
+    // closes the open dispute at `index`; the transaction stays disputed while others are open
+    fn settle_dispute(&mut self, trx_id: TransactionId, index: usize, state: TransactionState) {
+        if let Some(transaction) = self.transactions.get_mut(&trx_id) {
+            let dispute = transaction.disputes.remove(index);
+            // settled disputes never exceed the amount, so the sums always fit
+            let settled = match state {
+                TransactionState::ChargedBack => &mut transaction.charged_back,
+                _ => &mut transaction.resolved,
+            };
+            *settled = settled
+                .checked_add(dispute.amount)
+                .expect("settled amount overflowed");
+            if transaction.disputes.is_empty() {
+                transaction.state = state;
+            }
         }
+    }
+
+    // the open dispute a resolve or chargeback settles - the oldest one with the amount of the
+    // record, or the oldest one when the record has no amount
+    fn settled_dispute(
+        record: &Record,
+        disputed: &Transaction,
+        log_header: &str,
+    ) -> Result<usize, TransactionError> {
+        disputed
+            .disputes
+            .iter()
+            .position(|dispute| record.amount.is_none_or(|amount| amount == dispute.amount))
+            .ok_or_else(|| {
+                log::debug!(
+                    "{}: no open dispute has the record amount, record == {}",
+                    log_header,
+                    record
+                );
+                TransactionError::UnknownDispute
+            })
+    }
+
+    // the amount of a new dispute - the record amount, or everything that was never disputed
+    fn dispute_amount(
+        record: &Record,
+        disputed: &Transaction,
+        log_header: &str,
+    ) -> Result<Money, TransactionError> {
+        let undisputed = disputed.undisputed().ok_or(TransactionError::Overflow)?;
+        let amount = record.amount.unwrap_or(undisputed);
+        if amount <= Money::ZERO || amount > undisputed {
+            log::debug!(
+                "{}: dispute amount is not within the undisputed amount, amount == {}, undisputed == {}",
+                log_header,
+                amount,
+                undisputed
+            );
+            return Err(TransactionError::InvalidDisputeAmount);
+        }
+        Ok(amount)
+    }
+
+    // how much of a disputed deposit can be held, following the negative balance policy
+    fn deposit_hold(
+        &self,
+        balance: &Balance,
+        amount: Money,
+        log_header: &str,
+    ) -> Result<Money, TransactionError> {
+        match self.policy.negative_balances {
+            NegativeBalances::Allow => Ok(amount),
+            NegativeBalances::Reject if balance.available < amount => {
+                log::debug!(
+                    "{}: dispute would overdraw the account, available == {}, amount == {}",
+                    log_header,
+                    balance.available,
+                    amount
+                );
+                Err(TransactionError::InsufficientFunds)
+            }
+            NegativeBalances::Reject => Ok(amount),
+            NegativeBalances::Cap => Ok(amount.min(balance.available.max(Money::ZERO))),
+        }
+    }
+
+    // applies the new balances of a dispute, resolve or chargeback, refusing any overflow
+    fn move_funds(
+        &mut self,
+        currency: Option<Currency>,
+        available: Option<Money>,
+        held: Option<Money>,
+        receivable: Option<Money>,
+        log_header: &str,
+    ) -> Result<(), TransactionError> {
+        let (available, held, receivable) = match (available, held, receivable) {
+            (Some(available), Some(held), Some(receivable))
+                if available.checked_add(held).is_some() =>
+            {
+                (available, held, receivable)
+            }
+            _ => {
+                let balance = self.balance(currency);
+                log::warn!(
+                    "{}: record would overflow the balance, available == {}, held == {}",
+                    log_header,
+                    balance.available,
+                    balance.held
+                );
+                return Err(TransactionError::Overflow);
+            }
+        };
+
+        self.set_balance(
+            currency,
+            Balance {
+                available,
+                held,
+                receivable,
+            },
+            log_header,
+        );
+        Ok(())
+    }
+
+    fn dispute(&mut self, record: &Record) -> Result<(), TransactionError> {
+        let log_header = "Account::dispute";
+        let disputed = self.find_transaction(record, TransactionState::Disputed, log_header)?;
 
         log::debug!(
             "{}: disputed transaction found, will change available and held amounts",
             log_header
         );
-        // if a dispute was added -> it was already checked the deposited has correct RecordType
-        let deposited = self.transactions.get(&disputed.unwrap().trx_id);
-        let amount = deposited.unwrap().amount.unwrap();
+        if let (Some(window), Some(processed), Some(now)) = (
+            self.policy.dispute_window,
+            disputed.timestamp,
+            record.timestamp,
+        ) {
+            if now > processed.saturating_add(window) {
+                log::debug!(
+                    "{}: dispute window has closed, processed == {}, now == {}",
+                    log_header,
+                    processed,
+                    now
+                );
+                return Err(TransactionError::DisputeWindowClosed);
+            }
+        }
 
-        log::debug!(
-            "{}: old available == {}, new available == {}",
-            log_header,
-            self.available,
-            self.available + amount
+        let amount = Self::dispute_amount(record, &disputed, log_header)?;
+        let balance = self.balance(disputed.currency);
+        let (hold, available, held, receivable) = match disputed.record_type {
+            RecordType::Deposit | RecordType::Transfer => {
+                let hold = self.deposit_hold(&balance, amount, log_header)?;
+                (
+                    hold,
+                    balance.available.checked_sub(hold),
+                    balance.held.checked_add(hold),
+                    amount
+                        .checked_sub(hold)
+                        .and_then(|shortfall| balance.receivable.checked_add(shortfall)),
+                )
+            }
+            // reversed hold - the withdrawn funds are held until the dispute settles
+            _ => (
+                amount,
+                Some(balance.available),
+                balance.held.checked_add(amount),
+                Some(balance.receivable),
+            ),
+        };
+        self.move_funds(disputed.currency, available, held, receivable, log_header)?;
+        let deadline = record
+            .timestamp
+            .zip(self.policy.hold_period)
+            .map(|(now, hold_period)| now.saturating_add(hold_period));
+        self.open_dispute(
+            record,
+            Dispute {
+                amount,
+                held: hold,
+                deadline,
+            },
         );
-        self.available += amount;
+        Ok(())
+    }
+
+    fn resolve(&mut self, record: &Record) -> Result<(), TransactionError> {
+        let log_header = "Account::resolve";
+        let disputed = self.find_transaction(record, TransactionState::Resolved, log_header)?;
+        let index = Self::settled_dispute(record, &disputed, log_header)?;
+        self.resolve_dispute(record, &disputed, index, log_header)
+    }
+
+    fn resolve_dispute(
+        &mut self,
+        record: &Record,
+        disputed: &Transaction,
+        index: usize,
+        log_header: &str,
+    ) -> Result<(), TransactionError> {
         log::debug!(
-            "{}: old held == {}, new held == {}",
-            log_header,
-            self.held,
-            self.held - amount
+            "{}: disputed transaction found, will change available and held amounts",
+            log_header
         );
-        self.held -= amount;
-        true
+        let dispute = disputed.disputes[index];
+        let hold = dispute.held;
+        let balance = self.balance(disputed.currency);
+        let (available, held, receivable) = match disputed.record_type {
+            // the client keeps the deposit, so nothing is owed for a capped hold
+            RecordType::Deposit | RecordType::Transfer => (
+                balance.available.checked_add(hold),
+                balance.held.checked_sub(hold),
+                dispute
+                    .amount
+                    .checked_sub(hold)
+                    .and_then(|shortfall| balance.receivable.checked_sub(shortfall)),
+            ),
+            // the withdrawal stands, the reversed hold is dropped
+            _ => (
+                Some(balance.available),
+                balance.held.checked_sub(hold),
+                Some(balance.receivable),
+            ),
+        };
+        self.move_funds(disputed.currency, available, held, receivable, log_header)?;
+        self.settle_dispute(record.trx_id, index, TransactionState::Resolved);
+        Ok(())
     }
 
-    fn chargeback(&mut self, record: &Record) -> bool {
+    fn chargeback(&mut self, record: &Record) -> Result<(), TransactionError> {
         let log_header = "Account::chargeback";
-
-        let disputed = self.disputes.get(&record.trx_id);
-
-        if disputed.is_none() || disputed.unwrap().record_type != RecordType::Dispute {
-            log::debug!("{}: disputed transaction not found", log_header);
-            return false;
-        }
+        let disputed = self.find_transaction(record, TransactionState::ChargedBack, log_header)?;
+        let index = Self::settled_dispute(record, &disputed, log_header)?;
 
         log::debug!(
             "{}: disputed transaction found, will change available and held amounts",
             log_header
         );
-        let deposited = self.transactions.get(&disputed.unwrap().trx_id);
-
-        log::debug!(
-            "{}: old held == {}, new held == {}",
+        let hold = disputed.disputes[index].held;
+        let balance = self.balance(disputed.currency);
+        let (available, held) = match disputed.record_type {
+            // a shortfall of a capped hold stays receivable
+            RecordType::Deposit | RecordType::Transfer => {
+                (Some(balance.available), balance.held.checked_sub(hold))
+            }
+            // the withdrawn funds are credited back to the client
+            _ => (
+                balance.available.checked_add(hold),
+                balance.held.checked_sub(hold),
+            ),
+        };
+        self.move_funds(
+            disputed.currency,
+            available,
+            held,
+            Some(balance.receivable),
             log_header,
-            self.held,
-            self.held - deposited.unwrap().amount.unwrap()
-        );
-        self.held -= deposited.unwrap().amount.unwrap();
+        )?;
+        self.settle_dispute(record.trx_id, index, TransactionState::ChargedBack);
+        log::debug!("{}: locking this Account", log_header);
+        self.locked = true;
+        Ok(())
+    }
+
+    fn unlock(&mut self) -> Result<(), TransactionError> {
+        let log_header = "Account::unlock";
+        if !self.locked {
+            log::debug!("{}: Account is not locked", log_header);
+            return Err(TransactionError::NotLocked);
+        }
+
+        log::debug!("{}: unlocking this Account", log_header);
+        self.locked = false;
+        Ok(())
+    }
+
+    fn freeze(&mut self) -> Result<(), TransactionError> {
+        let log_header = "Account::freeze";
+        if self.locked {
+            log::debug!("{}: Account is already locked", log_header);
+            return Err(TransactionError::AccountLocked);
+        }
+
         log::debug!("{}: locking this Account", log_header);
         self.locked = true;
-        true
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), TransactionError> {
+        let log_header = "Account::close";
+        log::debug!("{}: closing this Account", log_header);
+        self.locked = true;
+        self.closed = true;
+        Ok(())
     }
 }
 
@@ -230,22 +1070,24 @@ mod tests {
 
     fn setup_record(
         record_type: RecordType,
-        client_id: u16,
-        trx_id: u16,
-        amount: Option<f64>,
+        client_id: ClientId,
+        trx_id: TransactionId,
+        amount: Option<Money>,
     ) -> Record {
         Record {
-            record_type: record_type,
-            client_id: client_id,
-            trx_id: trx_id,
-            amount: amount,
+            amount,
+            ..Record::new(record_type, client_id, trx_id)
         }
     }
 
-    fn setup(record_type: RecordType) -> (f64, Account, Record) {
+    fn money(amount: &str) -> Money {
+        amount.parse().unwrap()
+    }
+
+    fn setup(record_type: RecordType) -> (Money, Account, Record) {
         let client_id = 1;
         let trx_id = 1;
-        let amount = 100.;
+        let amount = money("100");
 
         let account = Account::new(client_id);
 
@@ -260,82 +1102,97 @@ mod tests {
     fn process_deposit_increases_available_and_total() {
         let (amount, mut account, record) = setup(RecordType::Deposit);
 
-        account.process(record);
+        assert_eq!(Ok(()), account.process(&record));
 
         assert_eq!(1, account.transactions.len());
-        assert!(account.disputes.is_empty());
-        assert_eq!(amount, account.available);
-        assert_eq!(0., account.held);
-        assert_eq!(amount, account.total());
+        assert_eq!(
+            Some(TransactionState::Processed),
+            account.transaction_state(record.trx_id)
+        );
+        assert_eq!(amount, account.balance(None).available);
+        assert_eq!(Money::ZERO, account.balance(None).held);
+        assert_eq!(amount, account.balance(None).total());
     }
 
     #[test]
     fn process_withdrawal_doesnt_decrease_when_no_available_funds() {
         let (_, mut account, record) = setup(RecordType::Withdrawal);
 
-        account.process(record);
+        assert_eq!(
+            Err(TransactionError::InsufficientFunds),
+            account.process(&record)
+        );
 
         assert!(account.transactions.is_empty());
-        assert!(account.disputes.is_empty());
-        assert_eq!(0., account.available);
-        assert_eq!(0., account.held);
-        assert_eq!(0., account.total());
+        assert_eq!(Money::ZERO, account.balance(None).available);
+        assert_eq!(Money::ZERO, account.balance(None).held);
+        assert_eq!(Money::ZERO, account.balance(None).total());
     }
 
     #[test]
     fn process_withdrawal_decreases_available_and_total() {
         let (amount, mut account, record) = setup(RecordType::Withdrawal);
-        account.available = amount;
+        account.set_balance(
+            None,
+            Balance {
+                available: amount,
+                ..Balance::default()
+            },
+            "test",
+        );
 
-        account.process(record);
+        assert_eq!(Ok(()), account.process(&record));
 
         assert_eq!(1, account.transactions.len());
-        assert!(account.disputes.is_empty());
-        assert_eq!(0., account.available);
-        assert_eq!(0., account.held);
-        assert_eq!(0., account.total());
+        assert_eq!(
+            Some(TransactionState::Processed),
+            account.transaction_state(record.trx_id)
+        );
+        assert_eq!(Money::ZERO, account.balance(None).available);
+        assert_eq!(Money::ZERO, account.balance(None).held);
+        assert_eq!(Money::ZERO, account.balance(None).total());
     }
 
     #[test]
     fn process_dispute_with_invalid_trx_id() {
         let (_, mut account, record) = setup(RecordType::Dispute);
 
-        account.process(record);
+        assert_eq!(
+            Err(TransactionError::UnknownTransaction),
+            account.process(&record)
+        );
 
         assert!(account.transactions.is_empty());
-        assert!(account.disputes.is_empty());
-        assert_eq!(0., account.available);
-        assert_eq!(0., account.held);
+        assert_eq!(Money::ZERO, account.balance(None).available);
+        assert_eq!(Money::ZERO, account.balance(None).held);
     }
 
     #[test]
     fn process_dispute_not_a_deposit() {
-        let (_, mut account, record) = setup(RecordType::Deposit);
+        let (amount, mut account, record) = setup(RecordType::Deposit);
         let trx_id = record.trx_id;
         let client_id = record.client_id;
-
-        account.process(record);
-
-        assert_eq!(1, account.transactions.len());
-        assert!(account.disputes.is_empty());
-
-        for record_type in [
-            RecordType::Chargeback,
-            RecordType::Finished,
-            RecordType::Invalid,
-            RecordType::Resolve,
-            RecordType::Withdrawal,
-            RecordType::Dispute,
-        ] {
-            let dispute_record = setup_record(RecordType::Dispute, client_id, trx_id, None);
-
-            let record_in_account: &mut Record = account.transactions.get_mut(&trx_id).unwrap();
-            record_in_account.record_type = record_type;
-
-            account.process(dispute_record);
-            assert_eq!(1, account.transactions.len());
-            assert!(account.disputes.is_empty());
-        }
+        let withdrawal_record =
+            setup_record(RecordType::Withdrawal, client_id, trx_id + 1, Some(amount));
+
+        assert_eq!(Ok(()), account.process(&record));
+        assert_eq!(Ok(()), account.process(&withdrawal_record));
+
+        assert_eq!(
+            Err(TransactionError::NotADeposit),
+            account.process(&setup_record(
+                RecordType::Dispute,
+                client_id,
+                trx_id + 1,
+                None
+            ))
+        );
+        assert_eq!(
+            Some(TransactionState::Processed),
+            account.transaction_state(trx_id + 1)
+        );
+        assert_eq!(Money::ZERO, account.balance(None).available);
+        assert_eq!(Money::ZERO, account.balance(None).held);
     }
 
     #[test]
@@ -347,32 +1204,41 @@ mod tests {
         let dispute_record = setup_record(RecordType::Dispute, client_id, trx_id, None);
         let resolve_record = setup_record(RecordType::Resolve, client_id, trx_id, None);
 
-        account.process(deposit_record);
+        assert_eq!(Ok(()), account.process(&deposit_record));
 
         assert_eq!(1, account.transactions.len());
-        assert!(account.disputes.is_empty());
+        assert_eq!(
+            Some(TransactionState::Processed),
+            account.transaction_state(trx_id)
+        );
 
-        assert_eq!(amount, account.available);
-        assert_eq!(0., account.held);
-        assert_eq!(amount, account.total());
+        assert_eq!(amount, account.balance(None).available);
+        assert_eq!(Money::ZERO, account.balance(None).held);
+        assert_eq!(amount, account.balance(None).total());
 
-        account.process(dispute_record);
+        assert_eq!(Ok(()), account.process(&dispute_record));
 
         assert_eq!(1, account.transactions.len());
-        assert_eq!(1, account.disputes.len());
+        assert_eq!(
+            Some(TransactionState::Disputed),
+            account.transaction_state(trx_id)
+        );
 
-        assert_eq!(0., account.available);
-        assert_eq!(amount, account.held);
-        assert_eq!(amount, account.total());
+        assert_eq!(Money::ZERO, account.balance(None).available);
+        assert_eq!(amount, account.balance(None).held);
+        assert_eq!(amount, account.balance(None).total());
 
-        account.process(resolve_record);
+        assert_eq!(Ok(()), account.process(&resolve_record));
 
         assert_eq!(1, account.transactions.len());
-        assert_eq!(1, account.disputes.len());
+        assert_eq!(
+            Some(TransactionState::Resolved),
+            account.transaction_state(trx_id)
+        );
 
-        assert_eq!(amount, account.available);
-        assert_eq!(0., account.held);
-        assert_eq!(amount, account.total());
+        assert_eq!(amount, account.balance(None).available);
+        assert_eq!(Money::ZERO, account.balance(None).held);
+        assert_eq!(amount, account.balance(None).total());
     }
 
     #[test]
@@ -384,46 +1250,71 @@ mod tests {
         let dispute_record = setup_record(RecordType::Dispute, client_id, trx_id, None);
         let chargeback_record = setup_record(RecordType::Chargeback, client_id, trx_id, None);
 
-        account.process(deposit_record);
+        assert_eq!(Ok(()), account.process(&deposit_record));
 
         assert_eq!(1, account.transactions.len());
-        assert!(account.disputes.is_empty());
+        assert_eq!(
+            Some(TransactionState::Processed),
+            account.transaction_state(trx_id)
+        );
 
-        assert_eq!(amount, account.available);
-        assert_eq!(0., account.held);
-        assert_eq!(amount, account.total());
+        assert_eq!(amount, account.balance(None).available);
+        assert_eq!(Money::ZERO, account.balance(None).held);
+        assert_eq!(amount, account.balance(None).total());
 
-        account.process(dispute_record);
+        assert_eq!(Ok(()), account.process(&dispute_record));
 
         assert_eq!(1, account.transactions.len());
-        assert_eq!(1, account.disputes.len());
+        assert_eq!(
+            Some(TransactionState::Disputed),
+            account.transaction_state(trx_id)
+        );
 
-        assert_eq!(0., account.available);
-        assert_eq!(amount, account.held);
-        assert_eq!(amount, account.total());
+        assert_eq!(Money::ZERO, account.balance(None).available);
+        assert_eq!(amount, account.balance(None).held);
+        assert_eq!(amount, account.balance(None).total());
 
-        account.process(chargeback_record);
+        assert_eq!(Ok(()), account.process(&chargeback_record));
 
         assert_eq!(1, account.transactions.len());
-        assert_eq!(1, account.disputes.len());
+        assert_eq!(
+            Some(TransactionState::ChargedBack),
+            account.transaction_state(trx_id)
+        );
 
-        assert_eq!(0., account.available);
-        assert_eq!(0., account.held);
-        assert_eq!(0., account.total());
+        assert_eq!(Money::ZERO, account.balance(None).available);
+        assert_eq!(Money::ZERO, account.balance(None).held);
+        assert_eq!(Money::ZERO, account.balance(None).total());
         assert!(account.locked);
 
         for record in [
-            setup_record(RecordType::Deposit, client_id, trx_id + 1, Some(100.)),
-            setup_record(RecordType::Withdrawal, client_id, trx_id + 1, Some(100.)),
+            setup_record(
+                RecordType::Deposit,
+                client_id,
+                trx_id + 1,
+                Some(money("100")),
+            ),
+            setup_record(
+                RecordType::Withdrawal,
+                client_id,
+                trx_id + 1,
+                Some(money("100")),
+            ),
         ] {
-            account.process(record);
+            assert_eq!(
+                Err(TransactionError::AccountLocked),
+                account.process(&record)
+            );
 
             assert_eq!(1, account.transactions.len());
-            assert_eq!(1, account.disputes.len());
+            assert_eq!(
+                Some(TransactionState::ChargedBack),
+                account.transaction_state(trx_id)
+            );
 
-            assert_eq!(0., account.available);
-            assert_eq!(0., account.held);
-            assert_eq!(0., account.total());
+            assert_eq!(Money::ZERO, account.balance(None).available);
+            assert_eq!(Money::ZERO, account.balance(None).held);
+            assert_eq!(Money::ZERO, account.balance(None).total());
             assert!(account.locked);
         }
     }
@@ -433,25 +1324,706 @@ mod tests {
         let (_, mut account, mut record) = setup(RecordType::Deposit);
         record.amount = None;
 
-        account.process(record);
+        assert_eq!(
+            Err(TransactionError::MissingAmount),
+            account.process(&record)
+        );
 
         assert!(account.transactions.is_empty());
-        assert!(account.disputes.is_empty());
 
-        assert_eq!(0., account.available);
+        assert_eq!(Money::ZERO, account.balance(None).available);
     }
 
     #[test]
     fn process_withdrawal_with_none_amount() {
         let (amount, mut account, mut record) = setup(RecordType::Withdrawal);
-        account.available = amount;
+        account.set_balance(
+            None,
+            Balance {
+                available: amount,
+                ..Balance::default()
+            },
+            "test",
+        );
         record.amount = None;
 
-        account.process(record);
+        assert_eq!(
+            Err(TransactionError::MissingAmount),
+            account.process(&record)
+        );
 
         assert!(account.transactions.is_empty());
-        assert!(account.disputes.is_empty());
 
-        assert_eq!(amount, account.available);
+        assert_eq!(amount, account.balance(None).available);
+    }
+
+    #[test]
+    fn process_deposit_and_withdrawal_refuse_amounts_that_are_not_positive() {
+        let mut account = Account::new(1);
+        assert_eq!(
+            Ok(()),
+            account.process(&setup_record(RecordType::Deposit, 1, 1, Some(money("10"))))
+        );
+
+        for (record_type, amount) in [
+            (RecordType::Deposit, "-5"),
+            (RecordType::Deposit, "0"),
+            (RecordType::Withdrawal, "-7"),
+            (RecordType::Withdrawal, "0"),
+        ] {
+            assert_eq!(
+                Err(TransactionError::InvalidAmount),
+                account.process(&setup_record(record_type, 1, 2, Some(money(amount))))
+            );
+        }
+        assert_eq!(1, account.transactions.len());
+        assert_eq!(money("10"), account.balance(None).available);
+    }
+
+    #[test]
+    fn process_deposit_with_duplicate_trx_id_keeps_original() {
+        let (amount, mut account, record) = setup(RecordType::Deposit);
+        let client_id = record.client_id;
+        let trx_id = record.trx_id;
+
+        assert_eq!(Ok(()), account.process(&record));
+        assert_eq!(
+            Err(TransactionError::DuplicateTransaction),
+            account.process(&setup_record(
+                RecordType::Deposit,
+                client_id,
+                trx_id,
+                Some(money("5"))
+            ))
+        );
+
+        assert_eq!(1, account.transactions.len());
+        assert_eq!(amount, account.transactions[&trx_id].amount);
+        assert_eq!(amount, account.balance(None).available);
+    }
+
+    #[test]
+    fn process_resolve_and_chargeback_without_dispute() {
+        let (amount, mut account, record) = setup(RecordType::Deposit);
+        let client_id = record.client_id;
+        let trx_id = record.trx_id;
+
+        assert_eq!(Ok(()), account.process(&record));
+
+        for record_type in [RecordType::Resolve, RecordType::Chargeback] {
+            assert_eq!(
+                Err(TransactionError::NotDisputed),
+                account.process(&setup_record(record_type, client_id, trx_id, None))
+            );
+            assert_eq!(
+                Err(TransactionError::UnknownTransaction),
+                account.process(&setup_record(record_type, client_id, trx_id + 1, None))
+            );
+        }
+
+        assert_eq!(amount, account.balance(None).available);
+        assert_eq!(Money::ZERO, account.balance(None).held);
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn process_dispute_twice_is_rejected() {
+        let (amount, mut account, record) = setup(RecordType::Deposit);
+        let dispute_record =
+            setup_record(RecordType::Dispute, record.client_id, record.trx_id, None);
+
+        assert_eq!(Ok(()), account.process(&record));
+        assert_eq!(Ok(()), account.process(&dispute_record));
+        assert_eq!(
+            Err(TransactionError::IllegalTransition {
+                from: TransactionState::Disputed,
+                to: TransactionState::Disputed
+            }),
+            account.process(&dispute_record)
+        );
+
+        assert_eq!(Money::ZERO, account.balance(None).available);
+        assert_eq!(amount, account.balance(None).held);
+    }
+
+    #[test]
+    fn process_resolved_dispute_is_closed() {
+        let (amount, mut account, record) = setup(RecordType::Deposit);
+        let client_id = record.client_id;
+        let trx_id = record.trx_id;
+
+        for record_type in [
+            RecordType::Deposit,
+            RecordType::Dispute,
+            RecordType::Resolve,
+        ] {
+            let amount = (record_type == RecordType::Deposit).then_some(amount);
+            assert_eq!(
+                Ok(()),
+                account.process(&setup_record(record_type, client_id, trx_id, amount))
+            );
+        }
+
+        for (record_type, to) in [
+            (RecordType::Dispute, TransactionState::Disputed),
+            (RecordType::Resolve, TransactionState::Resolved),
+            (RecordType::Chargeback, TransactionState::ChargedBack),
+        ] {
+            assert_eq!(
+                Err(TransactionError::IllegalTransition {
+                    from: TransactionState::Resolved,
+                    to
+                }),
+                account.process(&setup_record(record_type, client_id, trx_id, None))
+            );
+        }
+
+        assert_eq!(
+            Some(TransactionState::Resolved),
+            account.transaction_state(trx_id)
+        );
+        assert_eq!(amount, account.balance(None).available);
+        assert_eq!(Money::ZERO, account.balance(None).held);
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn transaction_state_transitions() {
+        use TransactionState::*;
+
+        for (from, to, legal) in [
+            (Processed, Disputed, true),
+            (Processed, Resolved, false),
+            (Processed, ChargedBack, false),
+            (Disputed, Disputed, false),
+            (Disputed, Resolved, true),
+            (Disputed, ChargedBack, true),
+            (Resolved, Disputed, false),
+            (Resolved, ChargedBack, false),
+            (ChargedBack, Disputed, false),
+            (ChargedBack, Resolved, false),
+        ] {
+            assert_eq!(legal, from.can_become(to), "{} -> {}", from, to);
+        }
+    }
+
+    fn reversed_hold_setup() -> Account {
+        let amount = money("40");
+        let mut account = Account::with_policy(
+            1,
+            Policy {
+                withdrawal_disputes: WithdrawalDisputes::ReversedHold,
+                ..Policy::default()
+            },
+        );
+
+        assert_eq!(
+            Ok(()),
+            account.process(&setup_record(RecordType::Deposit, 1, 1, Some(money("100"))))
+        );
+        assert_eq!(
+            Ok(()),
+            account.process(&setup_record(RecordType::Withdrawal, 1, 2, Some(amount)))
+        );
+        assert_eq!(
+            Ok(()),
+            account.process(&setup_record(RecordType::Dispute, 1, 2, None))
+        );
+
+        assert_eq!(money("60"), account.balance(None).available);
+        assert_eq!(amount, account.balance(None).held);
+        assert_eq!(money("100"), account.balance(None).total());
+        account
+    }
+
+    #[test]
+    fn process_withdrawal_dispute_reversed_hold_resolve() {
+        let mut account = reversed_hold_setup();
+
+        assert_eq!(
+            Ok(()),
+            account.process(&setup_record(RecordType::Resolve, 1, 2, None))
+        );
+
+        assert_eq!(
+            Some(TransactionState::Resolved),
+            account.transaction_state(2)
+        );
+        assert_eq!(money("60"), account.balance(None).available);
+        assert_eq!(Money::ZERO, account.balance(None).held);
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn process_withdrawal_dispute_reversed_hold_chargeback() {
+        let mut account = reversed_hold_setup();
+
+        assert_eq!(
+            Ok(()),
+            account.process(&setup_record(RecordType::Chargeback, 1, 2, None))
+        );
+
+        assert_eq!(
+            Some(TransactionState::ChargedBack),
+            account.transaction_state(2)
+        );
+        assert_eq!(money("100"), account.balance(None).available);
+        assert_eq!(Money::ZERO, account.balance(None).held);
+        assert!(account.locked);
+    }
+
+    // deposits 100, withdraws 70 and disputes the deposit
+    fn overdrawing_dispute(negative_balances: NegativeBalances) -> (Account, Record) {
+        let mut account = Account::with_policy(
+            1,
+            Policy {
+                negative_balances,
+                ..Policy::default()
+            },
+        );
+        assert_eq!(
+            Ok(()),
+            account.process(&setup_record(RecordType::Deposit, 1, 1, Some(money("100"))))
+        );
+        assert_eq!(
+            Ok(()),
+            account.process(&setup_record(
+                RecordType::Withdrawal,
+                1,
+                2,
+                Some(money("70"))
+            ))
+        );
+
+        (account, setup_record(RecordType::Dispute, 1, 1, None))
+    }
+
+    #[test]
+    fn process_overdrawing_dispute_allowed() {
+        let (mut account, dispute_record) = overdrawing_dispute(NegativeBalances::Allow);
+
+        assert_eq!(Ok(()), account.process(&dispute_record));
+
+        let snapshot = account.snapshot(None);
+        assert_eq!(money("-70"), snapshot.available);
+        assert_eq!(money("100"), snapshot.held);
+        assert_eq!(Money::ZERO, snapshot.receivable);
+        assert!(snapshot.overdrawn);
+    }
+
+    #[test]
+    fn process_overdrawing_dispute_rejected() {
+        let (mut account, dispute_record) = overdrawing_dispute(NegativeBalances::Reject);
+
+        assert_eq!(
+            Err(TransactionError::InsufficientFunds),
+            account.process(&dispute_record)
+        );
+
+        assert_eq!(
+            Some(TransactionState::Processed),
+            account.transaction_state(1)
+        );
+        let snapshot = account.snapshot(None);
+        assert_eq!(money("30"), snapshot.available);
+        assert_eq!(Money::ZERO, snapshot.held);
+        assert!(!snapshot.overdrawn);
+    }
+
+    #[test]
+    fn process_overdrawing_dispute_capped_and_resolved() {
+        let (mut account, dispute_record) = overdrawing_dispute(NegativeBalances::Cap);
+
+        assert_eq!(Ok(()), account.process(&dispute_record));
+
+        let snapshot = account.snapshot(None);
+        assert_eq!(Money::ZERO, snapshot.available);
+        assert_eq!(money("30"), snapshot.held);
+        assert_eq!(money("70"), snapshot.receivable);
+        assert!(!snapshot.overdrawn);
+
+        assert_eq!(
+            Ok(()),
+            account.process(&setup_record(RecordType::Resolve, 1, 1, None))
+        );
+
+        let snapshot = account.snapshot(None);
+        assert_eq!(money("30"), snapshot.available);
+        assert_eq!(Money::ZERO, snapshot.held);
+        assert_eq!(Money::ZERO, snapshot.receivable);
+    }
+
+    #[test]
+    fn process_overdrawing_dispute_capped_and_charged_back() {
+        let (mut account, dispute_record) = overdrawing_dispute(NegativeBalances::Cap);
+
+        assert_eq!(Ok(()), account.process(&dispute_record));
+        assert_eq!(
+            Ok(()),
+            account.process(&setup_record(RecordType::Chargeback, 1, 1, None))
+        );
+
+        let snapshot = account.snapshot(None);
+        assert_eq!(Money::ZERO, snapshot.available);
+        assert_eq!(Money::ZERO, snapshot.held);
+        assert_eq!(money("70"), snapshot.receivable);
+        assert!(snapshot.locked);
+    }
+
+    #[test]
+    fn process_unlock_after_chargeback() {
+        let (amount, mut account, record) = setup(RecordType::Deposit);
+        let deposit_record = setup_record(RecordType::Deposit, 1, 2, Some(amount));
+        let unlock_record = setup_record(RecordType::Unlock, 1, 3, None);
+
+        for record_type in [
+            RecordType::Deposit,
+            RecordType::Dispute,
+            RecordType::Chargeback,
+        ] {
+            let amount = (record_type == RecordType::Deposit).then_some(amount);
+            assert_eq!(
+                Ok(()),
+                account.process(&setup_record(record_type, 1, record.trx_id, amount))
+            );
+        }
+        assert_eq!(
+            Err(TransactionError::AccountLocked),
+            account.process(&deposit_record)
+        );
+
+        assert_eq!(Ok(()), account.process(&unlock_record));
+        assert!(!account.locked);
+        assert_eq!(Ok(()), account.process(&deposit_record));
+        assert_eq!(amount, account.balance(None).available);
+
+        assert_eq!(
+            Err(TransactionError::NotLocked),
+            account.process(&unlock_record)
+        );
+    }
+
+    #[test]
+    fn process_freeze_and_close() {
+        let (amount, mut account, record) = setup(RecordType::Deposit);
+
+        assert_eq!(
+            Ok(()),
+            account.process(&setup_record(RecordType::Freeze, 1, 2, None))
+        );
+        assert_eq!(
+            Err(TransactionError::AccountLocked),
+            account.process(&setup_record(RecordType::Freeze, 1, 3, None))
+        );
+        assert_eq!(
+            Err(TransactionError::AccountLocked),
+            account.process(&record)
+        );
+
+        assert_eq!(
+            Ok(()),
+            account.process(&setup_record(RecordType::Close, 1, 4, None))
+        );
+        for record_type in [RecordType::Unlock, RecordType::Freeze, RecordType::Close] {
+            assert_eq!(
+                Err(TransactionError::AccountClosed),
+                account.process(&setup_record(record_type, 1, 5, None))
+            );
+        }
+        assert_eq!(
+            Err(TransactionError::AccountClosed),
+            account.process(&setup_record(RecordType::Deposit, 1, 6, Some(amount)))
+        );
+        assert!(account.locked);
+        assert_eq!(Money::ZERO, account.balance(None).available);
+    }
+
+    #[test]
+    fn process_partial_disputes() {
+        let (_, mut account, record) = setup(RecordType::Deposit);
+        let trx_id = record.trx_id;
+        let settle = |record_type, amount: Option<&str>| {
+            setup_record(record_type, 1, trx_id, amount.map(money))
+        };
+        let dispute = |amount| settle(RecordType::Dispute, amount);
+        let balance = |account: &Account| {
+            let balance = account.balance(None);
+            (balance.available, balance.held)
+        };
+
+        assert_eq!(Ok(()), account.process(&record));
+        assert_eq!(
+            Err(TransactionError::InvalidDisputeAmount),
+            account.process(&dispute(Some("100.0001")))
+        );
+        // an empty dispute would hold nothing and still mark the transaction disputed
+        for amount in ["-1", "0"] {
+            assert_eq!(
+                Err(TransactionError::InvalidDisputeAmount),
+                account.process(&dispute(Some(amount)))
+            );
+        }
+        assert_eq!(
+            Some(TransactionState::Processed),
+            account.transaction_state(trx_id)
+        );
+
+        // two disputes open at once, up to the undisputed rest
+        assert_eq!(Ok(()), account.process(&dispute(Some("30"))));
+        assert_eq!(Ok(()), account.process(&dispute(Some("50"))));
+        assert_eq!(Some(money("80")), account.under_dispute(trx_id));
+        assert_eq!((money("20"), money("80")), balance(&account));
+        assert_eq!(
+            Err(TransactionError::InvalidDisputeAmount),
+            account.process(&dispute(Some("20.0001")))
+        );
+
+        // a resolve with an amount settles the dispute of that amount only
+        assert_eq!(
+            Err(TransactionError::UnknownDispute),
+            account.process(&settle(RecordType::Resolve, Some("10")))
+        );
+        assert_eq!(
+            Ok(()),
+            account.process(&settle(RecordType::Resolve, Some("50")))
+        );
+        assert_eq!(Some(money("30")), account.under_dispute(trx_id));
+        assert_eq!((money("70"), money("30")), balance(&account));
+        assert_eq!(
+            Some(TransactionState::Disputed),
+            account.transaction_state(trx_id)
+        );
+
+        // the resolved 50 is closed, only the 20 never disputed is left to dispute
+        assert_eq!(
+            Err(TransactionError::InvalidDisputeAmount),
+            account.process(&dispute(Some("20.0001")))
+        );
+        assert_eq!(Ok(()), account.process(&dispute(None)));
+        assert_eq!(Some(money("50")), account.under_dispute(trx_id));
+        assert_eq!((money("50"), money("50")), balance(&account));
+
+        // without an amount the oldest open dispute is settled
+        assert_eq!(
+            Ok(()),
+            account.process(&settle(RecordType::Chargeback, None))
+        );
+        assert_eq!((money("50"), money("20")), balance(&account));
+        assert_eq!(
+            Some(TransactionState::Disputed),
+            account.transaction_state(trx_id)
+        );
+        assert!(account.locked);
+
+        account.locked = false;
+        assert_eq!(Ok(()), account.process(&settle(RecordType::Resolve, None)));
+        assert_eq!((money("70"), Money::ZERO), balance(&account));
+        assert_eq!(
+            Some(TransactionState::Resolved),
+            account.transaction_state(trx_id)
+        );
+
+        // every part of the amount was disputed once, nothing is left to dispute
+        assert_eq!(
+            Err(TransactionError::IllegalTransition {
+                from: TransactionState::Resolved,
+                to: TransactionState::Disputed
+            }),
+            account.process(&dispute(None))
+        );
+    }
+
+    #[test]
+    fn process_dispute_window() {
+        let deadline = 120 * crate::policy::SECONDS_PER_DAY;
+        let mut account = Account::with_policy(
+            1,
+            Policy {
+                dispute_window: Some(deadline),
+                ..Policy::default()
+            },
+        );
+        let at = |record_type, trx_id, amount: Option<&str>, timestamp| Record {
+            timestamp,
+            ..setup_record(record_type, 1, trx_id, amount.map(money))
+        };
+
+        assert_eq!(
+            Ok(()),
+            account.process(&at(RecordType::Deposit, 1, Some("10"), Some(0)))
+        );
+        assert_eq!(
+            Ok(()),
+            account.process(&at(RecordType::Deposit, 2, Some("10"), None))
+        );
+
+        assert_eq!(
+            Err(TransactionError::DisputeWindowClosed),
+            account.process(&at(RecordType::Dispute, 1, None, Some(deadline + 1)))
+        );
+        // without both timestamps there is nothing to check
+        assert_eq!(
+            Ok(()),
+            account.process(&at(RecordType::Dispute, 2, None, Some(deadline + 1)))
+        );
+        assert_eq!(
+            Ok(()),
+            account.process(&at(RecordType::Dispute, 1, None, Some(deadline)))
+        );
+        assert_eq!(money("20"), account.balance(None).held);
+    }
+
+    #[test]
+    fn process_keeps_a_balance_per_currency() {
+        let mut account = Account::new(1);
+        let eur = Some("EUR".parse::<Currency>().unwrap());
+        let usd = Some("USD".parse::<Currency>().unwrap());
+        let in_currency = |record_type, trx_id, amount: Option<&str>, currency| Record {
+            currency,
+            ..setup_record(record_type, 1, trx_id, amount.map(money))
+        };
+
+        assert_eq!(
+            Ok(()),
+            account.process(&in_currency(RecordType::Deposit, 1, Some("10"), eur))
+        );
+        assert_eq!(
+            Ok(()),
+            account.process(&in_currency(RecordType::Deposit, 2, Some("5"), usd))
+        );
+        assert_eq!(
+            Err(TransactionError::InsufficientFunds),
+            account.process(&in_currency(RecordType::Withdrawal, 3, Some("6"), usd))
+        );
+
+        assert_eq!(
+            Err(TransactionError::CurrencyMismatch),
+            account.process(&in_currency(RecordType::Dispute, 2, None, eur))
+        );
+        assert_eq!(
+            Ok(()),
+            account.process(&in_currency(RecordType::Dispute, 2, None, None))
+        );
+
+        let snapshots = account.snapshots();
+        assert_eq!(
+            vec![eur, usd],
+            snapshots
+                .iter()
+                .map(|snapshot| snapshot.currency)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(money("10"), snapshots[0].available);
+        assert_eq!(Money::ZERO, snapshots[0].held);
+        assert_eq!(Money::ZERO, snapshots[1].available);
+        assert_eq!(money("5"), snapshots[1].held);
+        assert_eq!(Money::ZERO, account.balance(None).total());
+    }
+
+    #[test]
+    fn convert_moves_funds_between_currencies() {
+        let mut account = Account::new(1);
+        let eur = "EUR".parse::<Currency>().unwrap();
+        let pln = "PLN".parse::<Currency>().unwrap();
+        let convert = |amount| Record {
+            currency: Some(eur),
+            to_currency: Some(pln),
+            ..setup_record(RecordType::Convert, 1, 2, Some(money(amount)))
+        };
+        let deposit = Record {
+            currency: Some(eur),
+            ..setup_record(RecordType::Deposit, 1, 1, Some(money("10")))
+        };
+        assert_eq!(Ok(()), account.process(&deposit));
+
+        assert_eq!(
+            Err(TransactionError::UnsupportedRecord(RecordType::Convert)),
+            account.process(&convert("4"))
+        );
+        assert_eq!(
+            Err(TransactionError::InsufficientFunds),
+            account.convert(&convert("11"), money("47.3"))
+        );
+        // a negative amount would convert backwards and overdraw the target
+        assert_eq!(
+            Err(TransactionError::InvalidAmount),
+            account.convert(&convert("-10"), money("-43"))
+        );
+        assert_eq!(
+            Err(TransactionError::InvalidAmount),
+            account.convert(&convert("0"), Money::ZERO)
+        );
+        // converting into the same currency would take an id and move nothing
+        assert_eq!(
+            Err(TransactionError::SameCurrency),
+            account.convert(
+                &Record {
+                    to_currency: Some(eur),
+                    ..convert("1000")
+                },
+                money("1000")
+            )
+        );
+        assert_eq!(Ok(()), account.convert(&convert("4"), money("17.2")));
+        assert_eq!(money("6"), account.balance(Some(eur)).available);
+        assert_eq!(money("17.2"), account.balance(Some(pln)).available);
+
+        let mut rates = FxRates::default();
+        assert_eq!(None, account.consolidated(eur, &rates));
+        rates.insert(eur, pln, 0, "4.3".parse().unwrap());
+        let consolidated = account.consolidated(eur, &rates).unwrap();
+        assert_eq!(Some(eur), consolidated.currency);
+        assert_eq!(money("10"), consolidated.total);
+    }
+
+    #[test]
+    fn transfer_out_and_in_and_refund() {
+        let mut source = Account::new(1);
+        let mut destination = Account::new(2);
+        let transfer = Record {
+            to_client: Some(2),
+            ..setup_record(RecordType::Transfer, 1, 2, Some(money("4")))
+        };
+
+        assert_eq!(
+            Err(TransactionError::InsufficientFunds),
+            source.process(&transfer)
+        );
+        assert_eq!(
+            Ok(()),
+            source.process(&setup_record(RecordType::Deposit, 1, 1, Some(money("10"))))
+        );
+        assert_eq!(Ok(()), destination.check_transfer_in(&transfer));
+        assert_eq!(Ok(()), source.process(&transfer));
+        assert_eq!(Ok(()), destination.process(&transfer));
+        assert_eq!(money("6"), source.balance(None).available);
+        assert_eq!(money("4"), destination.balance(None).available);
+        assert_eq!(None, source.transaction_state(2));
+
+        // the destination disputes an incoming transfer like a deposit
+        for record_type in [RecordType::Dispute, RecordType::Chargeback] {
+            assert_eq!(
+                Ok(()),
+                destination.process(&setup_record(record_type, 2, 2, None))
+            );
+        }
+        assert_eq!(Money::ZERO, destination.balance(None).total());
+        assert!(destination.locked);
+        assert_eq!(
+            Err(TransactionError::AccountLocked),
+            destination.check_transfer_in(&Record {
+                trx_id: 3,
+                ..transfer.clone()
+            })
+        );
+
+        source.locked = true;
+        assert_eq!(Ok(()), source.refund(None, money("4")));
+        assert_eq!(money("10"), source.balance(None).available);
+        source.closed = true;
+        assert_eq!(
+            Err(TransactionError::AccountClosed),
+            source.check_refund(None, money("4"))
+        );
     }
 }
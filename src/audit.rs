@@ -0,0 +1,85 @@
+use std::{fs::File, path::Path};
+
+use crate::record::{ClientId, Record, RecordType, Timestamp, TransactionId};
+
+/// A change of the lock state of an account - who asked for it and where it came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub input: usize,
+    pub line: u64,
+    pub timestamp: Option<Timestamp>,
+    pub client_id: ClientId,
+    pub trx_id: TransactionId,
+    pub action: RecordType,
+    /// The authorizing operator, `None` for a chargeback even when the record names one.
+    pub operator: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new(record: &Record) -> Self {
+        Self {
+            input: record.input,
+            line: record.line,
+            timestamp: record.timestamp,
+            client_id: record.client_id,
+            trx_id: record.trx_id,
+            action: record.record_type,
+            operator: record
+                .authorization
+                .clone()
+                .filter(|_| record.record_type.is_admin()),
+        }
+    }
+}
+
+/// Writes the audit trail as csv - `input, line, timestamp, client, tx, action, operator`.
+pub struct AuditWriter {
+    writer: csv::Writer<File>,
+    inputs: Vec<String>,
+}
+
+impl AuditWriter {
+    const HEADER: [&'static str; 7] = [
+        "input",
+        "line",
+        "timestamp",
+        "client",
+        "tx",
+        "action",
+        "operator",
+    ];
+
+    /// `inputs` are the names of the inputs that `AuditEntry::input` indexes into.
+    pub fn create<P: AsRef<Path>, I: AsRef<Path>>(path: P, inputs: &[I]) -> csv::Result<Self> {
+        let mut writer = csv::WriterBuilder::new().from_path(path)?;
+        writer.write_record(Self::HEADER)?;
+        Ok(Self {
+            writer,
+            inputs: inputs
+                .iter()
+                .map(|input| input.as_ref().display().to_string())
+                .collect(),
+        })
+    }
+
+    pub fn write(&mut self, entry: &AuditEntry) -> csv::Result<()> {
+        let input = self.inputs.get(entry.input).map_or("", String::as_str);
+        self.writer.write_record([
+            input,
+            entry.line.to_string().as_str(),
+            entry
+                .timestamp
+                .map(|timestamp| timestamp.to_string())
+                .unwrap_or_default()
+                .as_str(),
+            entry.client_id.to_string().as_str(),
+            entry.trx_id.to_string().as_str(),
+            entry.action.name(),
+            entry.operator.as_deref().unwrap_or_default(),
+        ])
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
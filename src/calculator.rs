@@ -1,76 +1,1206 @@
 use std::{
-    collections::HashMap,
-    sync::{mpsc::Receiver, Arc, Mutex},
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    io::{Read, Write},
+    sync::{mpsc::Receiver, Arc},
 };
 
+use serde::{Deserialize, Serialize};
+
 use crate::{
-    account::Account,
-    record::{Record, RecordType},
+    account::{positive_amount, Account, AccountSnapshot, TransactionState},
+    audit::AuditEntry,
+    csvparser::Message,
+    currency::Currency,
+    error::{EngineError, TransactionError},
+    fx::{day_of, FxRates},
+    money::Money,
+    output::AccountSink,
+    policy::Policy,
+    record::{ClientId, Record, RecordType, Timestamp, TransactionId},
+    rejects::Rejection,
+    snapshot::{EngineState, SnapshotError},
 };
 
+/// Order of the accounts in the summary; every order falls back to the client id on ties.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    #[default]
+    ClientId,
+    Total,
+    Available,
+    Held,
+    /// The order in which the first record of every client was seen.
+    FirstSeen,
+}
+
+/// The transaction engine - owns every client `Account` and applies records to them in order.
+#[derive(Default)]
 pub struct Calculator {
-    receiver: Arc<Mutex<Receiver<Record>>>,
-    accounts: HashMap<u16, Account>,
+    accounts: HashMap<ClientId, Account>,
+    first_seen: Vec<ClientId>,
+    transaction_ids: HashSet<TransactionId>,
+    policy: Policy,
+    operators: HashSet<String>,
+    audit_trail: Vec<AuditEntry>,
+    // hold period deadlines of open disputes, earliest first
+    deadlines: BinaryHeap<Reverse<(Timestamp, ClientId, TransactionId)>>,
+    rates: Arc<FxRates>,
+    transfers: HashMap<TransactionId, Transfer>,
+}
+
+// the clients of a transfer, kept to find the accounts its dispute acts on
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) struct Transfer {
+    pub(crate) from: ClientId,
+    pub(crate) to: ClientId,
+    pub(crate) currency: Option<Currency>,
+}
+
+/// A client whose balances could not be converted to the reporting currency.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConversionError {
+    pub client_id: ClientId,
+    pub currency: Currency,
 }
 
+impl std::fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "balances of client {} can't be converted to {}",
+            self.client_id, self.currency
+        )
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
 impl Calculator {
-    pub fn new(receiver: Receiver<Record>) -> Self {
+    /// Creates an engine with no accounts and the default `Policy`.
+    pub fn new() -> Self {
+        Self::with_policy(Policy::default())
+    }
+
+    /// Creates an engine with no accounts whose accounts settle disputes by `policy`.
+    pub fn with_policy(policy: Policy) -> Self {
         Self {
-            receiver: Arc::new(Mutex::new(receiver)),
-            accounts: HashMap::<u16, Account>::new(),
+            accounts: HashMap::<ClientId, Account>::new(),
+            first_seen: Vec::<ClientId>::new(),
+            transaction_ids: HashSet::<TransactionId>::new(),
+            policy,
+            operators: HashSet::<String>::new(),
+            audit_trail: Vec::<AuditEntry>::new(),
+            deadlines: BinaryHeap::new(),
+            rates: Arc::new(FxRates::default()),
+            transfers: HashMap::<TransactionId, Transfer>::new(),
         }
     }
 
-    pub fn run(&mut self) {
-        loop {
-            let log_header = "Calculator::run";
-            log::debug!("{}: in the loop getting next record", log_header);
-            let next_record = self.receiver.lock().unwrap().recv().unwrap();
+    /// Uses `rates` for conversions and the consolidated view; without rates every conversion
+    /// is refused.
+    pub fn set_rates(&mut self, rates: Arc<FxRates>) {
+        self.rates = rates;
+    }
+
+    /// Allows `operator` to authorize administrative records; without any operator every
+    /// administrative record is refused.
+    pub fn authorize(&mut self, operator: impl Into<String>) {
+        self.operators.insert(operator.into());
+    }
+
+    /// Applies the records from `receiver` until `Message::Finished` arrives, then returns every
+    /// refused record. A failed parser, or a sender that goes away before the end, is an error.
+    pub fn run(&mut self, receiver: Receiver<Message>) -> Result<Vec<Rejection>, EngineError> {
+        let log_header = "Calculator::run";
+        let mut rejections = Vec::<Rejection>::new();
+        for message in receiver {
+            let batch = match message {
+                Message::Records(batch) => batch,
+                Message::Finished => {
+                    log::debug!("{}: Finished received", log_header);
+                    return Ok(rejections);
+                }
+                Message::Failed(reason) => {
+                    log::debug!("{}: the parser failed, reason == {}", log_header, reason);
+                    return Err(EngineError::ParserFailed(reason));
+                }
+            };
 
-            if next_record.record_type == RecordType::Invalid {
-                continue;
+            for next_record in batch {
+                if let Err(error) = self.calculate(&next_record) {
+                    log::debug!("{}: record rejected, error == {}", log_header, error);
+                    rejections.push(Rejection::refused(&next_record, error));
+                }
             }
+        }
+
+        log::debug!("{}: sender disconnected before the end", log_header);
+        Err(EngineError::ParserStopped)
+    }
 
-            if next_record.record_type == RecordType::Finished {
+    /// Applies a single record to the account of its client, creating the account on first use.
+    /// A refused record leaves every account untouched and the error says why.
+    ///
+    /// Deposit and withdrawal ids are unique across all clients - an id is taken by the first
+    /// record that carries it and is applied, and any later deposit or withdrawal with the same
+    /// id is refused as a duplicate. A refused record takes no id, so it can be sent again.
+    ///
+    /// Administrative records must name an authorized operator. Every change of a lock state is
+    /// added to the audit trail.
+    ///
+    /// A transfer is applied to both of its accounts or to none. It is kept, and disputed, by
+    /// the account it was paid to: a dispute, resolve or chargeback of a transfer may name
+    /// either client, and a chargeback also refunds the source account.
+    ///
+    /// A record with a timestamp first moves the clock of the engine to it, see `advance_to`.
+    pub fn calculate(&mut self, record: &Record) -> Result<(), TransactionError> {
+        let log_header = "Calculator::calculate";
+        log::debug!(
+            "{}: got a new record to calculate, record == {}",
+            log_header,
+            record
+        );
+        if let Some(now) = record.timestamp {
+            self.advance_to(now);
+        }
+
+        let takes_id = record.record_type.takes_id();
+        if takes_id && self.transaction_ids.contains(&record.trx_id) {
+            log::debug!(
+                "{}: transaction id already used, record == {}",
+                log_header,
+                record
+            );
+            return Err(TransactionError::DuplicateTransaction);
+        }
+
+        self.calculate_unique(record)?;
+        if takes_id {
+            self.transaction_ids.insert(record.trx_id);
+        }
+        Ok(())
+    }
+
+    // applies a record without checking its transaction id, for the workers of a
+    // `ShardedCalculator` whose router checks the ids of every worker at once
+    pub(crate) fn calculate_unique(&mut self, record: &Record) -> Result<(), TransactionError> {
+        let log_header = "Calculator::calculate_unique";
+        if record.record_type.is_admin()
+            && !record
+                .authorization
+                .as_ref()
+                .is_some_and(|operator| self.operators.contains(operator))
+        {
+            log::warn!(
+                "{}: administrative record without a known operator, record == {}",
+                log_header,
+                record
+            );
+            return Err(TransactionError::Unauthorized);
+        }
+
+        if record.record_type == RecordType::Transfer {
+            return self.transfer(record);
+        }
+
+        let transfer = disputed_transfer(&self.transfers, record);
+        let rerouted;
+        let record = match transfer {
+            Some(transfer) if transfer.to != record.client_id => {
+                rerouted = Record {
+                    client_id: transfer.to,
+                    ..record.clone()
+                };
+                &rerouted
+            }
+            _ => record,
+        };
+        let refund = match transfer {
+            Some(transfer) if record.record_type == RecordType::Chargeback => {
+                let amount = self.settled_amount(record).unwrap_or(Money::ZERO);
+                self.check_refund(transfer.from, transfer.currency, amount)?;
+                Some((transfer, amount))
+            }
+            _ => None,
+        };
+
+        log::debug!(
+            "{}: calling process for the account from the map, record == {}",
+            log_header,
+            record
+        );
+        let client_id = record.client_id;
+        let converted = match record.record_type {
+            RecordType::Convert => Some(self.converted_amount(record)?),
+            _ => None,
+        };
+        let account = self.account_mut(client_id);
+        match converted {
+            Some(converted) => account.convert(record, converted)?,
+            None => account.process(record)?,
+        }
+        let deadline = account.dispute_deadline(record.trx_id);
+
+        if let Some((transfer, amount)) = refund {
+            log::debug!(
+                "{}: refunding a charged back transfer, client == {}, amount == {}",
+                log_header,
+                transfer.from,
+                amount
+            );
+            // the refund was checked before the chargeback, so it can't be refused now
+            self.refund(transfer.from, transfer.currency, amount)
+                .expect("checked refund was refused");
+        }
+
+        if record.record_type == RecordType::Dispute {
+            if let Some(deadline) = deadline {
+                self.deadlines
+                    .push(Reverse((deadline, client_id, record.trx_id)));
+            }
+        }
+
+        if record.record_type.changes_lock() {
+            self.audit_trail.push(AuditEntry::new(record));
+        }
+        Ok(())
+    }
+
+    // moves the funds of a transfer, the destination is checked first so a refused transfer
+    // leaves both accounts untouched
+    fn transfer(&mut self, record: &Record) -> Result<(), TransactionError> {
+        let log_header = "Calculator::transfer";
+        let to_client = match record.to_client {
+            Some(to_client) if to_client != record.client_id => to_client,
+            _ => {
                 log::debug!(
-                    "{}: next_record received with record_type == RecordType::Finished",
-                    log_header
+                    "{}: transfer without a destination, record == {}",
+                    log_header,
+                    record
                 );
-                return self.finish();
+                return Err(TransactionError::InvalidTransfer);
             }
+        };
+
+        self.open_account(record.client_id);
+        positive_amount(record)?;
+        self.check_transfer_in(record, to_client)?;
+        self.transfer_out(record)?;
+        // the destination accepted the transfer above, so it can't be refused now
+        self.transfer_in(record, to_client)
+            .expect("checked transfer was refused");
+
+        self.transfers.insert(
+            record.trx_id,
+            Transfer {
+                from: record.client_id,
+                to: to_client,
+                currency: record.currency,
+            },
+        );
+        Ok(())
+    }
+
+    // the steps of a transfer and of the refund of its chargeback, also taken one by one when
+    // the two clients are kept by different engines of a `ShardedCalculator`
 
-            self.calculate(next_record)
+    // the source account of a transfer exists from then on, even when the transfer is refused
+    pub(crate) fn open_account(&mut self, client_id: ClientId) {
+        self.account_mut(client_id);
+    }
+
+    pub(crate) fn check_transfer_in(
+        &mut self,
+        record: &Record,
+        to_client: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.account_mut(to_client).check_transfer_in(record)
+    }
+
+    pub(crate) fn transfer_out(&mut self, record: &Record) -> Result<(), TransactionError> {
+        self.account_mut(record.client_id).process(record)
+    }
+
+    pub(crate) fn transfer_in(
+        &mut self,
+        record: &Record,
+        to_client: ClientId,
+    ) -> Result<(), TransactionError> {
+        self.account_mut(to_client).process(record)
+    }
+
+    pub(crate) fn check_refund(
+        &mut self,
+        client_id: ClientId,
+        currency: Option<Currency>,
+        amount: Money,
+    ) -> Result<(), TransactionError> {
+        self.account_mut(client_id).check_refund(currency, amount)
+    }
+
+    pub(crate) fn refund(
+        &mut self,
+        client_id: ClientId,
+        currency: Option<Currency>,
+        amount: Money,
+    ) -> Result<(), TransactionError> {
+        self.account_mut(client_id).refund(currency, amount)
+    }
+
+    /// Joins the engines of every shard of a `ShardedCalculator` into one. The shards keep
+    /// disjoint sets of clients; the ids, transfers and first seen order that span shards are
+    /// kept by the router and passed in. The audit trail is put in input order.
+    pub(crate) fn merge(
+        shards: Vec<Calculator>,
+        first_seen: Vec<ClientId>,
+        transaction_ids: HashSet<TransactionId>,
+        transfers: HashMap<TransactionId, Transfer>,
+    ) -> Calculator {
+        let mut shards = shards.into_iter();
+        let mut merged = shards.next().unwrap_or_default();
+        for shard in shards {
+            merged.accounts.extend(shard.accounts);
+            merged.audit_trail.extend(shard.audit_trail);
+            merged.deadlines.extend(shard.deadlines);
+            merged.transfers.extend(shard.transfers);
         }
+        merged.transfers.extend(transfers);
+        merged.transaction_ids = transaction_ids;
+        merged.first_seen = first_seen
+            .into_iter()
+            .filter(|client_id| merged.accounts.contains_key(client_id))
+            .collect();
+        merged
+            .audit_trail
+            .sort_by_key(|entry| (entry.input, entry.line));
+        merged
     }
 
-    fn calculate(&mut self, record: Record) {
-        let log_header = "Calculator::calculate";
+    // the engine spread over `parts` engines by the part `part_of` a client id, the reverse of
+    // `merge`; every part gets the policy, operators and rates. The first seen order, the
+    // transaction ids and the transfers between clients of different parts span the parts and
+    // are returned next to them
+    pub(crate) fn split(
+        self,
+        parts: usize,
+        part_of: impl Fn(ClientId) -> usize,
+    ) -> (
+        Vec<Calculator>,
+        Vec<ClientId>,
+        HashSet<TransactionId>,
+        HashMap<TransactionId, Transfer>,
+    ) {
+        let mut split: Vec<Calculator> = (0..parts.max(1))
+            .map(|_| {
+                let mut part = Calculator::with_policy(self.policy);
+                part.operators = self.operators.clone();
+                part.rates = Arc::clone(&self.rates);
+                part
+            })
+            .collect();
+        split[0].audit_trail = self.audit_trail;
+        for (client_id, account) in self.accounts {
+            split[part_of(client_id)]
+                .accounts
+                .insert(client_id, account);
+        }
+        for deadline in self.deadlines {
+            let Reverse((_, client_id, _)) = deadline;
+            split[part_of(client_id)].deadlines.push(deadline);
+        }
+        let mut transfers = HashMap::<TransactionId, Transfer>::new();
+        for (trx_id, transfer) in self.transfers {
+            let (from, to) = (part_of(transfer.from), part_of(transfer.to));
+            if from == to {
+                split[from].transfers.insert(trx_id, transfer);
+            } else {
+                transfers.insert(trx_id, transfer);
+            }
+        }
+        (split, self.first_seen, self.transaction_ids, transfers)
+    }
+
+    /// Writes every account - balances, lock flags, transaction history and open disputes -
+    /// with the transaction ids and transfers seen so far, as a versioned json snapshot. The
+    /// policy, operators, rates and audit trail belong to the run and are left out.
+    pub fn write_snapshot(&self, writer: impl Write) -> Result<(), SnapshotError> {
+        let log_header = "Calculator::write_snapshot";
+        let mut accounts: Vec<&Account> = self.accounts.values().collect();
+        accounts.sort_unstable_by_key(|account| account.id());
+        let mut transaction_ids: Vec<TransactionId> =
+            self.transaction_ids.iter().copied().collect();
+        transaction_ids.sort_unstable();
+        let mut transfers: Vec<(TransactionId, Transfer)> = self
+            .transfers
+            .iter()
+            .map(|(trx_id, transfer)| (*trx_id, *transfer))
+            .collect();
+        transfers.sort_unstable_by_key(|(trx_id, _)| *trx_id);
+
         log::debug!(
-            "{}: got a new record to calculate, record == {}",
+            "{}: writing {} accounts and {} transaction ids",
             log_header,
-            &record
+            accounts.len(),
+            transaction_ids.len()
         );
-        let client_id = record.client_id;
+        EngineState {
+            accounts: accounts.into_iter().map(Account::state).collect(),
+            first_seen: self.first_seen.clone(),
+            transaction_ids,
+            transfers,
+        }
+        .write(writer)
+    }
+
+    /// Replaces the accounts, transaction ids and transfers with those of a snapshot written
+    /// by `write_snapshot`, so the engine carries on where that run stopped. The policy,
+    /// operators and rates stay as they are; restored accounts settle disputes by this policy,
+    /// and open disputes keep the deadlines they were given.
+    pub fn restore_snapshot(&mut self, reader: impl Read) -> Result<(), SnapshotError> {
+        let log_header = "Calculator::restore_snapshot";
+        let state = EngineState::read(reader)?;
         log::debug!(
-            "{}: calling process for the account from the map, record == {}",
+            "{}: restoring {} accounts and {} transaction ids",
             log_header,
-            &record
+            state.accounts.len(),
+            state.transaction_ids.len()
         );
+
+        let policy = self.policy;
+        self.accounts = state
+            .accounts
+            .into_iter()
+            .map(|state| {
+                let account = Account::from_state(state, policy);
+                (account.id(), account)
+            })
+            .collect();
+        self.deadlines = self
+            .accounts
+            .iter()
+            .flat_map(|(client_id, account)| {
+                account
+                    .dispute_deadlines()
+                    .map(|(trx_id, deadline)| Reverse((deadline, *client_id, trx_id)))
+            })
+            .collect();
+        self.first_seen = state.first_seen;
+        self.transaction_ids = state.transaction_ids.into_iter().collect();
+        self.transfers = state.transfers.into_iter().collect();
+        Ok(())
+    }
+
+    // the account of a client, created on first use
+    fn account_mut(&mut self, client_id: ClientId) -> &mut Account {
+        let first_seen = &mut self.first_seen;
+        let policy = self.policy;
+        self.accounts.entry(client_id).or_insert_with(|| {
+            first_seen.push(client_id);
+            Account::with_policy(client_id, policy)
+        })
+    }
+
+    // the amount of a conversion in the target currency, with the rate in effect on the day of
+    // the record or the latest rate without a timestamp
+    fn converted_amount(&self, record: &Record) -> Result<Money, TransactionError> {
+        let (from, to) = record
+            .currency
+            .zip(record.to_currency)
+            .ok_or(TransactionError::MissingCurrency)?;
+        if from == to {
+            return Err(TransactionError::SameCurrency);
+        }
+        let amount = positive_amount(record)?;
+        self.rates
+            .convert(amount, from, to, record.timestamp.map(day_of))
+            .ok_or_else(|| {
+                log::debug!(
+                    "Calculator::converted_amount: no rate from {} to {}, record == {}",
+                    from,
+                    to,
+                    record
+                );
+                TransactionError::NoRate
+            })
+    }
+
+    /// Resolves every open dispute whose hold period ended at or before `now`. Returns how
+    /// many disputes were resolved.
+    pub fn advance_to(&mut self, now: Timestamp) -> usize {
+        let log_header = "Calculator::advance_to";
+        let mut resolved = 0;
+        while let Some(&Reverse((deadline, client_id, trx_id))) = self.deadlines.peek() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.pop();
+
+            if let Some(account) = self.accounts.get_mut(&client_id) {
+                if account.expire_dispute(trx_id, deadline) {
+                    log::debug!(
+                        "{}: hold period ended, client == {}, tx == {}",
+                        log_header,
+                        client_id,
+                        trx_id
+                    );
+                    resolved += 1;
+                }
+            }
+        }
+        resolved
+    }
+
+    /// Every change of a lock state, in the order the records were applied.
+    pub fn audit_trail(&self) -> &[AuditEntry] {
+        &self.audit_trail
+    }
+
+    /// Returns the dispute state of a deposit or withdrawal of the given client.
+    pub fn transaction_state(
+        &self,
+        client_id: ClientId,
+        trx_id: TransactionId,
+    ) -> Option<TransactionState> {
         self.accounts
-            .entry(client_id)
-            .or_insert_with(|| Account::new(client_id))
-            .process(record);
+            .get(&client_id)
+            .and_then(|account| account.transaction_state(trx_id))
     }
 
-    fn finish(&self) {
-        self.print_header();
+    /// Returns the part of a deposit or withdrawal of the given client under an open dispute.
+    pub fn under_dispute(&self, client_id: ClientId, trx_id: TransactionId) -> Option<Money> {
+        self.accounts
+            .get(&client_id)
+            .and_then(|account| account.under_dispute(trx_id))
+    }
+
+    // the amount of the open dispute a resolve or chargeback record would settle
+    pub(crate) fn settled_amount(&self, record: &Record) -> Option<Money> {
+        self.accounts
+            .get(&record.client_id)
+            .and_then(|account| account.settled_amount(record))
+    }
+
+    /// Returns the current balance of a single client in the given currency, if any record for
+    /// the client was seen; `None` as the currency is the balance of records without one.
+    pub fn account(
+        &self,
+        client_id: ClientId,
+        currency: Option<Currency>,
+    ) -> Option<AccountSnapshot> {
+        self.accounts
+            .get(&client_id)
+            .map(|account| account.snapshot(currency))
+    }
+
+    /// Iterates over the balances of every known client, one per client and currency, in no
+    /// particular order of clients.
+    pub fn accounts(&self) -> impl Iterator<Item = AccountSnapshot> + '_ {
+        self.accounts.values().flat_map(Account::snapshots)
+    }
 
-        for account in self.accounts.values() {
-            println!("{}", account);
+    /// Returns the balances of every known client in the given order, the balances of a client
+    /// are ordered by currency.
+    pub fn sorted_accounts(&self, order: SortOrder) -> Vec<AccountSnapshot> {
+        if order == SortOrder::FirstSeen {
+            return self
+                .first_seen
+                .iter()
+                .filter_map(|client_id| self.accounts.get(client_id))
+                .flat_map(Account::snapshots)
+                .collect();
         }
+
+        let mut accounts: Vec<AccountSnapshot> = self.accounts().collect();
+        sort_accounts(&mut accounts, order);
+        accounts
     }
 
-    fn print_header(&self) {
-        println!("client, available, held, total, locked");
+    /// Returns one balance per client with every currency converted to `currency` with the
+    /// latest rates, in the given order. Fails on the first client with a balance that has no
+    /// rate to `currency`.
+    pub fn consolidated_accounts(
+        &self,
+        order: SortOrder,
+        currency: Currency,
+    ) -> Result<Vec<AccountSnapshot>, ConversionError> {
+        let consolidate = |(client_id, account): (&ClientId, &Account)| {
+            account
+                .consolidated(currency, &self.rates)
+                .ok_or(ConversionError {
+                    client_id: *client_id,
+                    currency,
+                })
+        };
+        if order == SortOrder::FirstSeen {
+            return self
+                .first_seen
+                .iter()
+                .filter_map(|client_id| self.accounts.get_key_value(client_id))
+                .map(consolidate)
+                .collect();
+        }
+
+        let mut accounts = self
+            .accounts
+            .iter()
+            .map(consolidate)
+            .collect::<Result<Vec<AccountSnapshot>, ConversionError>>()?;
+        sort_accounts(&mut accounts, order);
+        Ok(accounts)
+    }
+
+    /// Writes the summary of every account to `sink` in the given order.
+    pub fn write_summary(
+        &self,
+        sink: &mut dyn AccountSink,
+        order: SortOrder,
+    ) -> std::io::Result<()> {
+        for account in self.sorted_accounts(order) {
+            sink.write(&account)?;
+        }
+        sink.finish()
+    }
+}
+
+// the transfer a dispute, resolve or chargeback by one of its clients refers to
+pub(crate) fn disputed_transfer(
+    transfers: &HashMap<TransactionId, Transfer>,
+    record: &Record,
+) -> Option<Transfer> {
+    if !matches!(
+        record.record_type,
+        RecordType::Dispute | RecordType::Resolve | RecordType::Chargeback
+    ) {
+        return None;
+    }
+    transfers
+        .get(&record.trx_id)
+        .copied()
+        .filter(|transfer| record.client_id == transfer.from || record.client_id == transfer.to)
+}
+
+// sorts balances by `order`, every order falls back to the client id and the currency on ties
+fn sort_accounts(accounts: &mut [AccountSnapshot], order: SortOrder) {
+    match order {
+        SortOrder::ClientId | SortOrder::FirstSeen => {
+            accounts.sort_by_key(|account| (account.client_id, account.currency))
+        }
+        SortOrder::Total => {
+            accounts.sort_by_key(|account| (account.total, account.client_id, account.currency))
+        }
+        SortOrder::Available => {
+            accounts.sort_by_key(|account| (account.available, account.client_id, account.currency))
+        }
+        SortOrder::Held => {
+            accounts.sort_by_key(|account| (account.held, account.client_id, account.currency))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deposit(client_id: ClientId, trx_id: TransactionId, amount: &str) -> Record {
+        Record {
+            amount: Some(amount.parse().unwrap()),
+            ..Record::new(RecordType::Deposit, client_id, trx_id)
+        }
+    }
+
+    fn setup() -> Calculator {
+        let mut calculator = Calculator::new();
+        for record in [
+            deposit(3, 1, "5"),
+            deposit(1, 2, "7"),
+            deposit(2, 3, "5"),
+            deposit(3, 4, "1"),
+        ] {
+            assert_eq!(Ok(()), calculator.calculate(&record));
+        }
+        calculator
+    }
+
+    fn client_ids(calculator: &Calculator, order: SortOrder) -> Vec<ClientId> {
+        calculator
+            .sorted_accounts(order)
+            .iter()
+            .map(|account| account.client_id)
+            .collect()
+    }
+
+    #[test]
+    fn sorted_accounts_by_client_id_by_default() {
+        let calculator = setup();
+
+        assert_eq!(vec![1, 2, 3], client_ids(&calculator, SortOrder::default()));
+    }
+
+    #[test]
+    fn sorted_accounts_by_balance_breaks_ties_by_client_id() {
+        let calculator = setup();
+
+        assert_eq!(vec![2, 3, 1], client_ids(&calculator, SortOrder::Total));
+        assert_eq!(vec![2, 3, 1], client_ids(&calculator, SortOrder::Available));
+        assert_eq!(vec![1, 2, 3], client_ids(&calculator, SortOrder::Held));
+    }
+
+    #[test]
+    fn calculate_rejects_transaction_id_reused_by_another_client() {
+        let mut calculator = setup();
+
+        assert_eq!(
+            Err(TransactionError::DuplicateTransaction),
+            calculator.calculate(&deposit(4, 1, "100"))
+        );
+        assert_eq!(
+            Err(TransactionError::DuplicateTransaction),
+            calculator.calculate(&Record {
+                record_type: RecordType::Withdrawal,
+                ..deposit(3, 2, "1")
+            })
+        );
+
+        assert_eq!(None, calculator.account(4, None));
+        assert_eq!(
+            "6.0000",
+            calculator.account(3, None).unwrap().available.to_string()
+        );
+    }
+
+    #[test]
+    fn calculate_leaves_the_id_of_a_refused_record_free() {
+        let mut calculator = setup();
+        calculator.authorize("alice");
+        let admin = |record_type, trx_id| Record {
+            amount: None,
+            authorization: Some(String::from("alice")),
+            ..Record::new(record_type, 1, trx_id)
+        };
+
+        assert_eq!(
+            Err(TransactionError::MissingAmount),
+            calculator.calculate(&Record {
+                amount: None,
+                ..deposit(1, 5, "0")
+            })
+        );
+        assert_eq!(Ok(()), calculator.calculate(&admin(RecordType::Freeze, 10)));
+        assert_eq!(
+            Err(TransactionError::AccountLocked),
+            calculator.calculate(&deposit(1, 6, "1"))
+        );
+        assert_eq!(Ok(()), calculator.calculate(&admin(RecordType::Unlock, 11)));
+
+        // the corrected records are sent again with the same ids, which only then are taken
+        for trx_id in [5, 6] {
+            assert_eq!(Ok(()), calculator.calculate(&deposit(1, trx_id, "1")));
+            assert_eq!(
+                Err(TransactionError::DuplicateTransaction),
+                calculator.calculate(&deposit(1, trx_id, "1"))
+            );
+        }
+        assert_eq!(
+            "9.0000",
+            calculator.account(1, None).unwrap().available.to_string()
+        );
+    }
+
+    #[test]
+    fn calculate_keeps_the_original_of_a_duplicate_for_disputes() {
+        let mut calculator = setup();
+
+        assert_eq!(
+            Err(TransactionError::DuplicateTransaction),
+            calculator.calculate(&deposit(3, 4, "1000"))
+        );
+        assert_eq!(
+            Ok(()),
+            calculator.calculate(&Record {
+                record_type: RecordType::Dispute,
+                amount: None,
+                ..deposit(3, 4, "0")
+            })
+        );
+
+        let account = calculator.account(3, None).unwrap();
+        assert_eq!("5.0000", account.available.to_string());
+        assert_eq!("1.0000", account.held.to_string());
+    }
+
+    #[test]
+    fn sorted_accounts_in_first_seen_order() {
+        let calculator = setup();
+
+        assert_eq!(vec![3, 1, 2], client_ids(&calculator, SortOrder::FirstSeen));
+    }
+
+    #[test]
+    fn calculate_admin_records_need_an_operator_and_are_audited() {
+        let mut calculator = setup();
+        calculator.authorize("alice");
+        let freeze = |trx_id, authorization: Option<&str>| Record {
+            record_type: RecordType::Freeze,
+            amount: None,
+            authorization: authorization.map(String::from),
+            line: 7,
+            ..deposit(1, trx_id, "0")
+        };
+
+        assert_eq!(
+            Err(TransactionError::Unauthorized),
+            calculator.calculate(&freeze(10, None))
+        );
+        assert_eq!(
+            Err(TransactionError::Unauthorized),
+            calculator.calculate(&freeze(11, Some("mallory")))
+        );
+        assert!(!calculator.account(1, None).unwrap().locked);
+        assert!(calculator.audit_trail().is_empty());
+
+        assert_eq!(Ok(()), calculator.calculate(&freeze(12, Some("alice"))));
+        assert!(calculator.account(1, None).unwrap().locked);
+        assert_eq!(
+            [AuditEntry {
+                input: 0,
+                line: 7,
+                timestamp: None,
+                client_id: 1,
+                trx_id: 12,
+                action: RecordType::Freeze,
+                operator: Some(String::from("alice")),
+            }],
+            calculator.audit_trail()
+        );
+
+        // a chargeback needs no operator, one it names anyway is not audited as such
+        for record_type in [RecordType::Dispute, RecordType::Chargeback] {
+            let record = Record {
+                record_type,
+                amount: None,
+                authorization: Some(String::from("mallory")),
+                ..deposit(2, 3, "0")
+            };
+            assert_eq!(Ok(()), calculator.calculate(&record));
+        }
+        assert_eq!(
+            Some(&AuditEntry {
+                input: 0,
+                line: 0,
+                timestamp: None,
+                client_id: 2,
+                trx_id: 3,
+                action: RecordType::Chargeback,
+                operator: None,
+            }),
+            calculator.audit_trail().last()
+        );
+    }
+
+    #[test]
+    fn calculate_expires_disputes_after_the_hold_period() {
+        let mut calculator = Calculator::with_policy(Policy {
+            hold_period: Some(100),
+            ..Policy::default()
+        });
+        let at = |record: Record, timestamp| Record {
+            timestamp: Some(timestamp),
+            ..record
+        };
+        let dispute = |client_id, trx_id| Record {
+            record_type: RecordType::Dispute,
+            amount: None,
+            ..deposit(client_id, trx_id, "0")
+        };
+
+        for record in [
+            at(deposit(1, 1, "10"), 0),
+            at(deposit(2, 2, "20"), 0),
+            at(dispute(1, 1), 10),
+            at(dispute(2, 2), 50),
+        ] {
+            assert_eq!(Ok(()), calculator.calculate(&record));
+        }
+        assert_eq!(Some(110), calculator.accounts[&1].dispute_deadline(1));
+
+        // a record of any client moves the clock, the deadline itself is inclusive
+        assert_eq!(Ok(()), calculator.calculate(&at(deposit(3, 3, "1"), 110)));
+        assert_eq!(
+            Some(TransactionState::Resolved),
+            calculator.transaction_state(1, 1)
+        );
+        assert_eq!(
+            Some(TransactionState::Disputed),
+            calculator.transaction_state(2, 2)
+        );
+        assert_eq!(
+            "10.0000",
+            calculator.account(1, None).unwrap().available.to_string()
+        );
+
+        assert_eq!(1, calculator.advance_to(150));
+        assert_eq!(
+            Some(TransactionState::Resolved),
+            calculator.transaction_state(2, 2)
+        );
+        assert_eq!(0, calculator.advance_to(1_000));
+
+        // every dispute of a transaction expires on its own
+        let partial = |amount: &str| Record {
+            amount: Some(amount.parse().unwrap()),
+            ..dispute(4, 4)
+        };
+        for record in [
+            at(deposit(4, 4, "10"), 2_000),
+            at(partial("4"), 2_000),
+            at(partial("6"), 2_050),
+        ] {
+            assert_eq!(Ok(()), calculator.calculate(&record));
+        }
+        assert_eq!(1, calculator.advance_to(2_100));
+        assert_eq!(Some("6".parse().unwrap()), calculator.under_dispute(4, 4));
+        assert_eq!(1, calculator.advance_to(2_150));
+        assert_eq!(
+            Some(TransactionState::Resolved),
+            calculator.transaction_state(4, 4)
+        );
+    }
+
+    #[test]
+    fn snapshot_restores_accounts_disputes_and_ids() {
+        let policy = Policy {
+            hold_period: Some(100),
+            ..Policy::default()
+        };
+        let mut calculator = Calculator::with_policy(policy);
+        let settle = |record_type, client_id, trx_id, timestamp| Record {
+            record_type,
+            amount: None,
+            timestamp: Some(timestamp),
+            ..deposit(client_id, trx_id, "0")
+        };
+        for record in [
+            deposit(1, 1, "10"),
+            deposit(4, 2, "5"),
+            Record {
+                record_type: RecordType::Transfer,
+                to_client: Some(3),
+                ..deposit(4, 3, "2")
+            },
+            settle(RecordType::Dispute, 1, 1, 10),
+            settle(RecordType::Dispute, 4, 2, 20),
+            settle(RecordType::Chargeback, 4, 2, 30),
+        ] {
+            assert_eq!(Ok(()), calculator.calculate(&record));
+        }
+
+        let mut snapshot = Vec::<u8>::new();
+        calculator.write_snapshot(&mut snapshot).unwrap();
+        let mut restored = Calculator::with_policy(policy);
+        restored.restore_snapshot(&snapshot[..]).unwrap();
+
+        for order in [SortOrder::ClientId, SortOrder::FirstSeen] {
+            assert_eq!(
+                calculator.sorted_accounts(order),
+                restored.sorted_accounts(order)
+            );
+        }
+        let mut rewritten = Vec::<u8>::new();
+        restored.write_snapshot(&mut rewritten).unwrap();
+        assert_eq!(snapshot, rewritten);
+
+        // ids stay taken, the open dispute keeps its deadline and the transfer its clients
+        assert_eq!(
+            Err(TransactionError::DuplicateTransaction),
+            restored.calculate(&deposit(5, 1, "1"))
+        );
+        assert_eq!(1, restored.advance_to(110));
+        assert_eq!(
+            Ok(()),
+            restored.calculate(&settle(RecordType::Dispute, 4, 3, 120))
+        );
+        assert_eq!(
+            Some(TransactionState::Disputed),
+            restored.transaction_state(3, 3)
+        );
+
+        assert!(matches!(
+            restored.restore_snapshot(&br#"{"version": 2}"#[..]),
+            Err(SnapshotError::Version(Some(2)))
+        ));
+        assert!(matches!(
+            restored.restore_snapshot(&b"{}"[..]),
+            Err(SnapshotError::Version(None))
+        ));
+        assert!(matches!(
+            restored.restore_snapshot(&snapshot[..snapshot.len() - 10]),
+            Err(SnapshotError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn calculate_converts_with_the_rate_of_the_day_and_consolidates() {
+        let eur: Currency = "EUR".parse().unwrap();
+        let usd: Currency = "USD".parse().unwrap();
+        let mut rates = FxRates::default();
+        rates.insert(eur, usd, 0, "1.1".parse().unwrap());
+        rates.insert(eur, usd, 1, "1.2".parse().unwrap());
+        let mut calculator = Calculator::new();
+        calculator.set_rates(Arc::new(rates));
+
+        let in_eur = |record: Record| Record {
+            currency: Some(eur),
+            ..record
+        };
+        let convert = |trx_id, to_currency, timestamp| Record {
+            record_type: RecordType::Convert,
+            to_currency,
+            timestamp,
+            ..in_eur(deposit(1, trx_id, "1"))
+        };
+        assert_eq!(Ok(()), calculator.calculate(&in_eur(deposit(1, 1, "10"))));
+        assert_eq!(
+            Ok(()),
+            calculator.calculate(&convert(2, Some(usd), Some(1_000)))
+        );
+        assert_eq!(
+            Err(TransactionError::DuplicateTransaction),
+            calculator.calculate(&convert(2, Some(usd), None))
+        );
+        assert_eq!(
+            Err(TransactionError::MissingCurrency),
+            calculator.calculate(&convert(3, None, None))
+        );
+        assert_eq!(
+            Err(TransactionError::InvalidAmount),
+            calculator.calculate(&Record {
+                amount: Some("-10".parse().unwrap()),
+                ..convert(7, Some(usd), None)
+            })
+        );
+        assert_eq!(
+            Err(TransactionError::NoRate),
+            calculator.calculate(&convert(4, Some("PLN".parse().unwrap()), None))
+        );
+        assert_eq!(
+            Err(TransactionError::SameCurrency),
+            calculator.calculate(&Record {
+                amount: Some("1000".parse().unwrap()),
+                ..convert(8, Some(eur), None)
+            })
+        );
+        assert_eq!(
+            "1.1000",
+            calculator
+                .account(1, Some(usd))
+                .unwrap()
+                .available
+                .to_string()
+        );
+
+        // the consolidated view uses the latest rate, 1.1 dollars back at 1.2 is 0.9167 euro
+        assert_eq!(Ok(()), calculator.calculate(&in_eur(deposit(2, 5, "1"))));
+        let consolidated = calculator
+            .consolidated_accounts(SortOrder::ClientId, eur)
+            .unwrap();
+        assert_eq!(2, consolidated.len());
+        assert_eq!("9.9167", consolidated[0].total.to_string());
+
+        assert_eq!(Ok(()), calculator.calculate(&deposit(2, 6, "1")));
+        assert_eq!(
+            Err(ConversionError {
+                client_id: 2,
+                currency: eur
+            }),
+            calculator.consolidated_accounts(SortOrder::ClientId, eur)
+        );
+    }
+
+    #[test]
+    fn calculate_transfers_atomically_and_disputes_them_as_a_unit() {
+        let mut calculator = Calculator::new();
+        let transfer = |from, to, trx_id, amount| Record {
+            record_type: RecordType::Transfer,
+            to_client: to,
+            ..deposit(from, trx_id, amount)
+        };
+        let settle = |record_type, client_id, trx_id| Record {
+            record_type,
+            amount: None,
+            ..deposit(client_id, trx_id, "0")
+        };
+        let available = |calculator: &Calculator, client_id| {
+            calculator
+                .account(client_id, None)
+                .unwrap()
+                .available
+                .to_string()
+        };
+
+        assert_eq!(Ok(()), calculator.calculate(&deposit(1, 1, "10")));
+        assert_eq!(
+            Err(TransactionError::InvalidTransfer),
+            calculator.calculate(&transfer(1, None, 2, "1"))
+        );
+        assert_eq!(
+            Err(TransactionError::InvalidTransfer),
+            calculator.calculate(&transfer(1, Some(1), 3, "1"))
+        );
+        assert_eq!(
+            Err(TransactionError::InsufficientFunds),
+            calculator.calculate(&transfer(1, Some(2), 4, "11"))
+        );
+        // a negative transfer would take the funds of the destination
+        for (trx_id, amount) in [(8, "-4"), (9, "0")] {
+            assert_eq!(
+                Err(TransactionError::InvalidAmount),
+                calculator.calculate(&transfer(1, Some(2), trx_id, amount))
+            );
+        }
+        assert_eq!(Ok(()), calculator.calculate(&transfer(1, Some(2), 5, "4")));
+        assert_eq!(
+            Err(TransactionError::DuplicateTransaction),
+            calculator.calculate(&transfer(1, Some(2), 5, "4"))
+        );
+        assert_eq!("6.0000", available(&calculator, 1));
+        assert_eq!("4.0000", available(&calculator, 2));
+
+        // a locked destination refuses the transfer and the source keeps its funds
+        assert_eq!(Ok(()), calculator.calculate(&deposit(3, 6, "1")));
+        for record_type in [RecordType::Dispute, RecordType::Chargeback] {
+            assert_eq!(Ok(()), calculator.calculate(&settle(record_type, 3, 6)));
+        }
+        assert_eq!(
+            Err(TransactionError::AccountLocked),
+            calculator.calculate(&transfer(1, Some(3), 7, "1"))
+        );
+        assert_eq!("6.0000", available(&calculator, 1));
+
+        // either client may dispute, the funds are held and refunded at the destination
+        assert_eq!(
+            Ok(()),
+            calculator.calculate(&settle(RecordType::Dispute, 1, 5))
+        );
+        assert_eq!(
+            Some(TransactionState::Disputed),
+            calculator.transaction_state(2, 5)
+        );
+        assert_eq!("0.0000", available(&calculator, 2));
+        assert_eq!(
+            Err(TransactionError::UnknownTransaction),
+            calculator.calculate(&settle(RecordType::Chargeback, 4, 5))
+        );
+        assert_eq!(
+            Ok(()),
+            calculator.calculate(&settle(RecordType::Chargeback, 2, 5))
+        );
+        assert_eq!("10.0000", available(&calculator, 1));
+        assert_eq!(
+            "0.0000",
+            calculator.account(2, None).unwrap().total.to_string()
+        );
+        assert!(calculator.account(2, None).unwrap().locked);
+        assert!(!calculator.account(1, None).unwrap().locked);
     }
 }
@@ -0,0 +1,201 @@
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+use log::LevelFilter;
+use transactioner::{
+    csvparser::DEFAULT_BATCH_SIZE, Currency, NegativeBalances, SortOrder, WithdrawalDisputes,
+};
+
+/// Exit code of a run where every row was applied, or rejected rows were tolerated.
+pub const EXIT_SUCCESS: u8 = 0;
+/// Exit code of a strict run that found rejected rows.
+pub const EXIT_DATA_ERROR: u8 = 1;
+/// Exit code of a run with invalid arguments - the same code `clap` uses.
+pub const EXIT_USAGE_ERROR: u8 = 2;
+/// Exit code of a run that could not read its input or write its output.
+pub const EXIT_IO_ERROR: u8 = 3;
+/// Exit code of a run whose parser or engine thread failed, nothing is written.
+pub const EXIT_PIPELINE_ERROR: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// `client,currency,available,held,total,locked,receivable,overdrawn` rows with a header
+    Csv,
+    /// A single json array of account objects
+    Json,
+    /// One json account object per line
+    Jsonl,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SortBy {
+    /// Ascending client id
+    Client,
+    /// Ascending total funds
+    Total,
+    /// Ascending available funds
+    Available,
+    /// Ascending held funds
+    Held,
+    /// The order in which every client first appeared in the input
+    FirstSeen,
+}
+
+impl From<SortBy> for SortOrder {
+    fn from(sort_by: SortBy) -> Self {
+        match sort_by {
+            SortBy::Client => SortOrder::ClientId,
+            SortBy::Total => SortOrder::Total,
+            SortBy::Available => SortOrder::Available,
+            SortBy::Held => SortOrder::Held,
+            SortBy::FirstSeen => SortOrder::FirstSeen,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum WithdrawalDisputePolicy {
+    /// Refuse disputes on withdrawals
+    Reject,
+    /// Hold the withdrawn amount, credit it back on chargeback
+    ReversedHold,
+}
+
+impl From<WithdrawalDisputePolicy> for WithdrawalDisputes {
+    fn from(policy: WithdrawalDisputePolicy) -> Self {
+        match policy {
+            WithdrawalDisputePolicy::Reject => WithdrawalDisputes::Reject,
+            WithdrawalDisputePolicy::ReversedHold => WithdrawalDisputes::ReversedHold,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum NegativeBalancePolicy {
+    /// Hold the whole disputed amount, available funds may go negative
+    Allow,
+    /// Refuse disputes that would overdraw the available funds
+    Reject,
+    /// Hold at most the available funds, record the rest as a receivable
+    Cap,
+}
+
+impl From<NegativeBalancePolicy> for NegativeBalances {
+    fn from(policy: NegativeBalancePolicy) -> Self {
+        match policy {
+            NegativeBalancePolicy::Allow => NegativeBalances::Allow,
+            NegativeBalancePolicy::Reject => NegativeBalances::Reject,
+            NegativeBalancePolicy::Cap => NegativeBalances::Cap,
+        }
+    }
+}
+
+fn parse_delimiter(value: &str) -> Result<char, String> {
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(delimiter), None) if delimiter.is_ascii() => Ok(delimiter),
+        _ => Err(String::from(
+            "the delimiter must be a single ascii character",
+        )),
+    }
+}
+
+/// Applies deposits, withdrawals, disputes, resolves and chargebacks from csv files
+/// and prints the resulting state of every client account.
+#[derive(Debug, Parser)]
+#[command(version, after_help = format!(
+    "Exit codes: {} success, {} rejected rows in --strict mode, {} usage error, {} i/o error, \
+     {} failed parser or engine thread",
+    EXIT_SUCCESS, EXIT_DATA_ERROR, EXIT_USAGE_ERROR, EXIT_IO_ERROR, EXIT_PIPELINE_ERROR
+))]
+pub struct Cli {
+    /// Csv files with `type, client, tx, amount` rows, processed in order; `-` reads stdin
+    #[arg(required = true, value_name = "INPUT")]
+    pub inputs: Vec<PathBuf>,
+
+    /// Write the account summary to this file instead of stdout
+    #[arg(short, long, value_name = "PATH")]
+    pub output: Option<PathBuf>,
+
+    /// Format of the account summary
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Csv)]
+    pub format: OutputFormat,
+
+    /// Field delimiter of the csv output, a single ascii character
+    #[arg(short, long, default_value_t = ',', value_parser = parse_delimiter)]
+    pub delimiter: char,
+
+    /// Order of the accounts in the summary, ties are broken by client id
+    #[arg(short, long, value_enum, default_value_t = SortBy::Client)]
+    pub sort: SortBy,
+
+    /// Write every rejected row with the reason to this csv file
+    #[arg(long, value_name = "PATH")]
+    pub rejects: Option<PathBuf>,
+
+    /// Put rows back in timestamp order, allowing a row to come this many seconds behind the
+    /// newest one; later rows are rejected
+    #[arg(long, value_name = "SECONDS")]
+    pub lateness: Option<u64>,
+
+    /// Days after a timestamped transaction during which it can be disputed
+    #[arg(long, value_name = "DAYS")]
+    pub dispute_window_days: Option<u64>,
+
+    /// Days after which an open timestamped dispute is resolved by itself
+    #[arg(long, value_name = "DAYS")]
+    pub hold_period_days: Option<u64>,
+
+    /// Write every change of an account lock state to this csv file
+    #[arg(long, value_name = "PATH")]
+    pub audit: Option<PathBuf>,
+
+    /// Start from the engine state in this snapshot file instead of empty accounts
+    #[arg(long, value_name = "PATH")]
+    pub restore: Option<PathBuf>,
+
+    /// Write the engine state after a successful run to this snapshot file
+    #[arg(long, value_name = "PATH")]
+    pub snapshot: Option<PathBuf>,
+
+    /// An operator allowed to authorize unlock, freeze and close records, may be repeated
+    #[arg(long = "operator", value_name = "NAME")]
+    pub operators: Vec<String>,
+
+    /// Csv file with `pair, rate, date` exchange rates, e.g. `EUR/USD, 1.0850, 2024-01-31`
+    #[arg(long, value_name = "PATH")]
+    pub fx_rates: Option<PathBuf>,
+
+    /// Write one row per client with every balance converted to this currency
+    #[arg(long, value_name = "CODE")]
+    pub reporting_currency: Option<Currency>,
+
+    /// Worker threads applying records, the clients are spread over them by client id
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    pub workers: u16,
+
+    /// Batches of records the parser may queue ahead of the engine, and each worker ahead of
+    /// the router, before waiting
+    #[arg(long, value_name = "BATCHES", default_value_t = 64, value_parser = clap::value_parser!(u32).range(1..))]
+    pub channel_capacity: u32,
+
+    /// Records the parser sends to the engine, and the engine to each worker, at once
+    #[arg(long, value_name = "RECORDS", default_value_t = DEFAULT_BATCH_SIZE as u32, value_parser = clap::value_parser!(u32).range(1..))]
+    pub batch_size: u32,
+
+    /// Log level (off, error, warn, info, debug, trace), overrides RUST_LOG
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+
+    /// How disputes on withdrawals are settled
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = WithdrawalDisputePolicy::Reject)]
+    pub withdrawal_disputes: WithdrawalDisputePolicy,
+
+    /// What a dispute does when it would overdraw the available funds
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = NegativeBalancePolicy::Allow)]
+    pub negative_balances: NegativeBalancePolicy,
+
+    /// Fail the run without writing a summary when any row is rejected
+    #[arg(long)]
+    pub strict: bool,
+}
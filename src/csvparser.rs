@@ -1,61 +1,472 @@
-use std::{env, sync::mpsc::Sender};
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::mpsc::{SyncSender, TrySendError},
+    time::{Duration, Instant},
+};
 
-use csv::{ReaderBuilder, Trim};
+use csv::{Reader, ReaderBuilder, StringRecord, Trim};
 
-use crate::record::{Record, RecordType};
+use crate::{
+    record::{Record, Timestamp},
+    rejects::{RejectReason, Rejection},
+    reorder::ReorderBuffer,
+};
 
+/// The input name that stands for stdin.
+pub const STDIN: &str = "-";
+
+/// Records sent to the engine at once unless set with `CSVParser::batch_size`.
+pub const DEFAULT_BATCH_SIZE: usize = 1024;
+
+/// How the parser fared sending batches of records over its bounded channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SendMetrics {
+    pub batches: u64,
+    pub records: u64,
+    /// Sends that found the channel full and had to wait for the engine.
+    pub blocked_sends: u64,
+    /// Time spent waiting on a full channel.
+    pub blocked: Duration,
+}
+
+/// What the parser sends to the engine. A parse ends with exactly one `Finished` or `Failed`;
+/// a channel that closes before either means the parser went away.
+#[derive(Debug)]
+pub enum Message {
+    /// The next records, in the order they are to be applied.
+    Records(Vec<Record>),
+    /// Every record of every input was sent.
+    Finished,
+    /// An input could not be read, the records sent so far are all there is.
+    Failed(String),
+}
+
+/// An input that could not be opened or read.
+#[derive(Debug)]
+pub struct InputError {
+    pub input: PathBuf,
+    pub error: csv::Error,
+}
+
+impl std::fmt::Display for InputError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "could not read {}: {}", self.input.display(), self.error)
+    }
+}
+
+impl std::error::Error for InputError {}
+
+/// Why the parser stopped before sending every record.
+#[derive(Debug)]
+pub enum ParseError {
+    /// An input could not be opened or read.
+    Input(InputError),
+    /// The engine stopped receiving records.
+    EngineStopped,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Input(error) => write!(f, "{}", error),
+            ParseError::EngineStopped => write!(f, "the engine stopped receiving records"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<InputError> for ParseError {
+    fn from(error: InputError) -> Self {
+        ParseError::Input(error)
+    }
+}
+
+/// Reads records from csv inputs, in order, and sends them to a `Calculator` in batches. The
+/// channel is bounded, so the parser waits whenever the engine falls behind.
 pub struct CSVParser {
-    sender: Sender<Record>,
+    sender: SyncSender<Message>,
+    inputs: Vec<PathBuf>,
+    reorder: Option<ReorderBuffer>,
+    batch: Vec<Record>,
+    batch_size: usize,
+    metrics: SendMetrics,
 }
 
 impl CSVParser {
-    pub fn new(sender: Sender<Record>) -> Self {
-        Self { sender }
+    /// `inputs` are file paths, `-` reads from stdin.
+    pub fn new<P: AsRef<Path>>(sender: SyncSender<Message>, inputs: &[P]) -> Self {
+        Self {
+            sender,
+            inputs: inputs
+                .iter()
+                .map(|input| input.as_ref().to_path_buf())
+                .collect(),
+            reorder: None,
+            batch: Vec::<Record>::with_capacity(DEFAULT_BATCH_SIZE),
+            batch_size: DEFAULT_BATCH_SIZE,
+            metrics: SendMetrics::default(),
+        }
     }
 
-    fn read_filename_from_args(&self) -> String {
-        let log_header = "CSVParser::read_filename_from_args";
-        let args: Vec<String> = env::args().collect();
-
-        // let's make sure we get at least a single input file
-        assert!(
-            args.len() > 1,
-            "You need to provide a path as an argument to a csv file to get the data from"
-        );
+    /// Sends records in batches of `batch_size`, at least one.
+    pub fn batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
 
-        let result = args[1].clone();
-        log::debug!("{}: input_filename set as == {}", log_header, result);
+    /// How the sends went so far.
+    pub fn metrics(&self) -> SendMetrics {
+        self.metrics
+    }
 
-        result
+    /// Sends records in timestamp order instead of input order, across all inputs. A record
+    /// may come up to `lateness` seconds behind the newest one, anything later is rejected.
+    pub fn reorder_by_timestamp(&mut self, lateness: Timestamp) {
+        self.reorder = Some(ReorderBuffer::new(lateness));
     }
 
-    pub fn parse_records(&mut self) {
+    /// Sends every row of every input, followed by `Message::Finished`. Rows that could not
+    /// be read are returned instead of being sent; an input that cannot be opened or read
+    /// stops the parse with `Message::Failed`, and so does an engine that went away.
+    pub fn parse_records(&mut self) -> Result<Vec<Rejection>, ParseError> {
         let log_header = "CSVParser::parse_records";
-        let input_filename = self.read_filename_from_args();
+        let mut rejections = Vec::<Rejection>::new();
 
-        let mut reader = ReaderBuilder::new()
-            .trim(Trim::All)
-            .from_path(&input_filename)
-            .unwrap();
+        for index in 0..self.inputs.len() {
+            let input = self.inputs[index].clone();
+            log::debug!("{}: reading input == {}", log_header, input.display());
+            let parsed = match Self::open(&input) {
+                Ok(reader) => self.parse_input(index, reader, &mut rejections),
+                Err(error) => Err(self.input_error(index, error)),
+            };
+            if let Err(error) = parsed {
+                if let ParseError::Input(error) = &error {
+                    // the engine may be gone already, the error is returned either way
+                    let _ = self.sender.send(Message::Failed(error.to_string()));
+                }
+                return Err(error);
+            }
+        }
 
-        reader.deserialize::<Record>().for_each(|record| {
-            let unpacked_record = record.unwrap_or_else(|_| Record::default());
+        if let Some(reorder) = &mut self.reorder {
             log::debug!(
-                "{}: parsed a new record == {}",
+                "{}: sending the {} records left in the reorder buffer",
                 log_header,
-                &unpacked_record
+                reorder.len()
             );
-            self.sender.send(unpacked_record).unwrap();
-        });
+            while let Some(record) = reorder.pop() {
+                self.batch.push(record);
+            }
+        }
+
+        self.flush()?;
+        log::debug!("{}: all records parsed, sending Finished", log_header);
+        self.sender
+            .send(Message::Finished)
+            .map_err(|_| ParseError::EngineStopped)?;
+
+        Ok(rejections)
+    }
+
+    fn input_error(&self, input: usize, error: csv::Error) -> ParseError {
+        ParseError::Input(InputError {
+            input: self.inputs[input].clone(),
+            error,
+        })
+    }
+
+    fn open(input: &Path) -> csv::Result<Reader<Box<dyn Read>>> {
+        let source: Box<dyn Read> = if input == Path::new(STDIN) {
+            Box::new(io::stdin().lock())
+        } else {
+            Box::new(File::open(input)?)
+        };
+
+        Ok(ReaderBuilder::new()
+            .trim(Trim::All)
+            .flexible(true)
+            .from_reader(source))
+    }
+
+    fn parse_input(
+        &mut self,
+        input: usize,
+        mut reader: Reader<Box<dyn Read>>,
+        rejections: &mut Vec<Rejection>,
+    ) -> Result<(), ParseError> {
+        let log_header = "CSVParser::parse_input";
+        let headers = reader
+            .headers()
+            .map_err(|error| self.input_error(input, error))?
+            .clone();
+
+        let mut row = StringRecord::new();
+        loop {
+            match reader.read_record(&mut row) {
+                Ok(true) => (),
+                Ok(false) => return Ok(()),
+                Err(error) if error.is_io_error() => return Err(self.input_error(input, error)),
+                Err(error) => {
+                    log::warn!("{}: skipping an unreadable row, {}", log_header, error);
+                    rejections.push(Rejection {
+                        input,
+                        line: error.position().map_or(0, |position| position.line()),
+                        fields: Vec::new(),
+                        reason: RejectReason::Malformed(error.to_string()),
+                    });
+                    continue;
+                }
+            }
+            let line = row.position().map_or(0, |position| position.line());
 
-        log::debug!(
-            "{}: all records parsed, sending Finished record",
-            log_header
+            // rows may leave out trailing optional fields, but never carry more than the header
+            let record = if row.len() > headers.len() {
+                Err(format!(
+                    "record has {} fields, but the header has {}",
+                    row.len(),
+                    headers.len()
+                ))
+            } else {
+                row.deserialize::<Record>(Some(&headers))
+                    .map_err(|error| error.to_string())
+            };
+            let record = match record {
+                Ok(record) => Record {
+                    input,
+                    line,
+                    ..record
+                },
+                Err(error) => {
+                    log::warn!(
+                        "{}: skipping a malformed record on line {}, {}",
+                        log_header,
+                        line,
+                        error
+                    );
+                    rejections.push(Rejection {
+                        input,
+                        line,
+                        fields: record_fields(&headers, &row),
+                        reason: RejectReason::Malformed(error),
+                    });
+                    continue;
+                }
+            };
+            log::debug!("{}: parsed a new record == {}", log_header, &record);
+            self.dispatch(record, rejections)?;
+        }
+    }
+
+    // sends a record with the next batch, or through the reorder buffer when there is one
+    fn dispatch(
+        &mut self,
+        record: Record,
+        rejections: &mut Vec<Rejection>,
+    ) -> Result<(), ParseError> {
+        let log_header = "CSVParser::dispatch";
+        let reorder = match &mut self.reorder {
+            Some(reorder) => reorder,
+            None => return self.push(record),
+        };
+
+        if let Err(record) = reorder.push(record) {
+            log::warn!(
+                "{}: skipping a record that arrived too late on line {}",
+                log_header,
+                record.line
+            );
+            rejections.push(Rejection::too_late(&record));
+        }
+        while let Some(record) = reorder.pop_ready() {
+            self.batch.push(record);
+        }
+        if self.batch.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, record: Record) -> Result<(), ParseError> {
+        self.batch.push(record);
+        if self.batch.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // sends the pending batch, timing how long a full channel keeps the parser waiting
+    fn flush(&mut self) -> Result<(), ParseError> {
+        let log_header = "CSVParser::flush";
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(self.batch_size));
+        self.metrics.batches += 1;
+        self.metrics.records += batch.len() as u64;
+
+        let sent = match self.sender.try_send(Message::Records(batch)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(message)) => {
+                log::debug!("{}: channel is full, waiting for the engine", log_header);
+                let started = Instant::now();
+                let sent = self.sender.send(message);
+                self.metrics.blocked_sends += 1;
+                self.metrics.blocked += started.elapsed();
+                sent.map_err(|_| ())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(()),
+        };
+        sent.map_err(|()| {
+            log::debug!("{}: the engine stopped receiving records", log_header);
+            ParseError::EngineStopped
+        })
+    }
+}
+
+// the fields of a malformed row laid out like `Record::fields`, columns it does not know dropped
+fn record_fields(headers: &StringRecord, row: &StringRecord) -> Vec<String> {
+    Record::FIELDS
+        .iter()
+        .map(|name| {
+            headers
+                .iter()
+                .position(|header| header == *name)
+                .and_then(|index| row.get(index))
+                .unwrap_or_default()
+                .to_string()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::mpsc::sync_channel};
+
+    use super::*;
+    use crate::{
+        calculator::Calculator,
+        record::{RecordType, TransactionId},
+    };
+
+    // writes `contents` to a file of its own in the temp directory
+    fn input(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("transactioner-{}-{}.csv", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn parse_records_rejects_rows_with_more_fields_than_the_header() {
+        let path = input(
+            "extra-fields",
+            "type,client,tx,amount\n\
+             deposit,1,1,5\n\
+             deposit,1,2,5,oops\n\
+             withdrawal,1,3\n",
+        );
+        let (sender, receiver) = sync_channel(4);
+
+        let rejections = CSVParser::new(sender, &[&path]).parse_records().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(1, rejections.len());
+        assert_eq!(3, rejections[0].line);
+        assert_eq!("malformed_record", rejections[0].reason.code());
+        assert_eq!(
+            "record has 5 fields, but the header has 4",
+            rejections[0].reason.to_string()
         );
-        let finish_record = Record {
-            record_type: RecordType::Finished,
-            ..Record::default()
+        let records: Vec<(RecordType, TransactionId)> = match receiver.recv().unwrap() {
+            Message::Records(records) => records
+                .iter()
+                .map(|record| (record.record_type, record.trx_id))
+                .collect(),
+            message => panic!("expected records, got {:?}", message),
         };
-        self.sender.send(finish_record).unwrap();
+        assert_eq!(
+            vec![(RecordType::Deposit, 1), (RecordType::Withdrawal, 3)],
+            records
+        );
+        assert!(matches!(receiver.recv().unwrap(), Message::Finished));
+    }
+
+    #[test]
+    fn parse_records_counts_batches_and_waits_on_a_full_channel() {
+        let path = input(
+            "metrics",
+            "type,client,tx,amount\n\
+             deposit,1,1,1\n\
+             deposit,1,2,1\n\
+             deposit,1,3,1\n\
+             deposit,1,4,1\n\
+             deposit,1,5,1\n",
+        );
+        let (sender, receiver) = sync_channel(1);
+
+        let parser = std::thread::spawn(move || {
+            let mut parser = CSVParser::new(sender, &[&path]);
+            parser.batch_size(2);
+            let rejections = parser.parse_records().unwrap();
+            fs::remove_file(&path).unwrap();
+            (rejections, parser.metrics())
+        });
+        // the first batch fills the channel, the parser has to wait to send the next one
+        std::thread::sleep(Duration::from_millis(100));
+        let mut batches = Vec::<usize>::new();
+        for message in receiver {
+            match message {
+                Message::Records(records) => batches.push(records.len()),
+                Message::Finished => break,
+                Message::Failed(reason) => panic!("the parse failed, {}", reason),
+            }
+        }
+        let (rejections, metrics) = parser.join().unwrap();
+
+        assert!(rejections.is_empty());
+        assert_eq!(vec![2, 2, 1], batches);
+        assert_eq!(3, metrics.batches);
+        assert_eq!(5, metrics.records);
+        assert!(metrics.blocked_sends > 0);
+    }
+
+    #[test]
+    fn parse_records_applies_the_inputs_in_order_to_one_engine() {
+        let first = input(
+            "first",
+            "type,client,tx,amount\n\
+             deposit,1,1,10\n\
+             dispute,1,1,\n",
+        );
+        // settles a dispute of the first input and spends funds it deposited
+        let second = input(
+            "second",
+            "type,client,tx,amount\n\
+             resolve,1,1,\n\
+             withdrawal,1,2,4\n\
+             deposit,1,1,1\n",
+        );
+        let (sender, receiver) = sync_channel(1);
+
+        let engine = std::thread::spawn(move || {
+            let mut calculator = Calculator::new();
+            let rejections = calculator.run(receiver).unwrap();
+            (calculator, rejections)
+        });
+        let parsed = CSVParser::new(sender, &[&first, &second]).parse_records();
+        let (calculator, rejections) = engine.join().unwrap();
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+
+        assert!(parsed.unwrap().is_empty());
+        let account = calculator.account(1, None).unwrap();
+        assert_eq!("6.0000", account.available.to_string());
+        assert_eq!("0.0000", account.held.to_string());
+        // the transaction id of the first input is taken in the second
+        assert_eq!(1, rejections.len());
+        assert_eq!((1, 4), (rejections[0].input, rejections[0].line));
+        assert_eq!("duplicate_transaction", rejections[0].reason.code());
     }
 }
@@ -0,0 +1,87 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Why a string could not be read as a `Currency`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CurrencyError(String);
+
+impl std::fmt::Display for CurrencyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "currency code must be three ascii letters, got \"{}\"",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for CurrencyError {}
+
+/// A three letter currency code such as `EUR`, always kept upper case.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub fn as_str(&self) -> &str {
+        // only ascii letters are ever stored
+        std::str::from_utf8(&self.0).expect("currency code is not ascii")
+    }
+}
+
+impl FromStr for Currency {
+    type Err = CurrencyError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let code: [u8; 3] = input
+            .trim()
+            .as_bytes()
+            .try_into()
+            .map_err(|_| CurrencyError(input.to_string()))?;
+        if !code.iter().all(u8::is_ascii_alphabetic) {
+            return Err(CurrencyError(input.to_string()));
+        }
+        Ok(Currency(code.map(|letter| letter.to_ascii_uppercase())))
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        input.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl std::fmt::Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_three_letters_upper_case() {
+        let currency: Currency = " eur".parse().unwrap();
+        assert_eq!("EUR", currency.to_string());
+        assert_eq!(currency, "EUR".parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        for input in ["", "EU", "EURO", "E1R", "€UR"] {
+            assert_eq!(
+                Err(CurrencyError(input.to_string())),
+                input.parse::<Currency>()
+            );
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::{account::TransactionState, record::RecordType};
+
+/// Why a record was refused by an `Account`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionError {
+    /// The account was locked by a chargeback or a freeze.
+    AccountLocked,
+    /// The account was closed, it takes no more records.
+    AccountClosed,
+    /// An unlock referenced an account that is not locked.
+    NotLocked,
+    /// An administrative record has no authorization from a known operator.
+    Unauthorized,
+    /// A deposit or withdrawal came without an amount.
+    MissingAmount,
+    /// A deposit, withdrawal, conversion or transfer came with an amount of zero or less.
+    InvalidAmount,
+    /// A withdrawal asked for more than the available funds, or a dispute would overdraw them.
+    InsufficientFunds,
+    /// The referenced transaction was never processed for this client.
+    UnknownTransaction,
+    /// A dispute referenced a transaction that is not a deposit.
+    NotADeposit,
+    /// A resolve or chargeback referenced a transaction that is not under dispute.
+    NotDisputed,
+    /// The referenced transaction can't move between these dispute states, e.g. a dispute
+    /// after a chargeback or a chargeback after a resolve.
+    IllegalTransition {
+        from: TransactionState,
+        to: TransactionState,
+    },
+    /// A transfer came without a destination client, or named its source as the destination.
+    InvalidTransfer,
+    /// A conversion came without its source or target currency.
+    MissingCurrency,
+    /// A conversion named the same currency as its source and target.
+    SameCurrency,
+    /// There is no exchange rate for the currencies of a conversion.
+    NoRate,
+    /// A dispute, resolve or chargeback named another currency than the transaction it refers to.
+    CurrencyMismatch,
+    /// A dispute came after the dispute window of the transaction had closed.
+    DisputeWindowClosed,
+    /// A dispute asked for an amount of zero or less, or for more than the part of the
+    /// transaction that was never disputed.
+    InvalidDisputeAmount,
+    /// A resolve or chargeback named an amount that no open dispute of the transaction has.
+    UnknownDispute,
+    /// The transaction id was already used by an earlier transaction.
+    DuplicateTransaction,
+    /// Applying the record would overflow a balance.
+    Overflow,
+    /// The record type cannot be applied to an account.
+    UnsupportedRecord(RecordType),
+}
+
+impl TransactionError {
+    /// A stable, machine-readable name of the error, used in the rejects report.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransactionError::AccountLocked => "account_locked",
+            TransactionError::AccountClosed => "account_closed",
+            TransactionError::NotLocked => "not_locked",
+            TransactionError::Unauthorized => "unauthorized",
+            TransactionError::MissingAmount => "missing_amount",
+            TransactionError::InvalidAmount => "invalid_amount",
+            TransactionError::InsufficientFunds => "insufficient_funds",
+            TransactionError::UnknownTransaction => "unknown_transaction",
+            TransactionError::NotADeposit => "not_a_deposit",
+            TransactionError::NotDisputed => "not_disputed",
+            TransactionError::IllegalTransition { .. } => "illegal_transition",
+            TransactionError::InvalidTransfer => "invalid_transfer",
+            TransactionError::MissingCurrency => "missing_currency",
+            TransactionError::SameCurrency => "same_currency",
+            TransactionError::NoRate => "no_rate",
+            TransactionError::CurrencyMismatch => "currency_mismatch",
+            TransactionError::DisputeWindowClosed => "dispute_window_closed",
+            TransactionError::InvalidDisputeAmount => "invalid_dispute_amount",
+            TransactionError::UnknownDispute => "unknown_dispute",
+            TransactionError::DuplicateTransaction => "duplicate_transaction",
+            TransactionError::Overflow => "overflow",
+            TransactionError::UnsupportedRecord(_) => "unsupported_record",
+        }
+    }
+}
+
+impl std::fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionError::AccountLocked => write!(f, "account is locked"),
+            TransactionError::AccountClosed => write!(f, "account is closed"),
+            TransactionError::NotLocked => write!(f, "account is not locked"),
+            TransactionError::Unauthorized => {
+                write!(f, "administrative record is not authorized")
+            }
+            TransactionError::MissingAmount => write!(f, "amount is missing"),
+            TransactionError::InvalidAmount => write!(f, "amount is not positive"),
+            TransactionError::InsufficientFunds => write!(f, "insufficient available funds"),
+            TransactionError::UnknownTransaction => write!(f, "transaction not found"),
+            TransactionError::NotADeposit => write!(f, "transaction is not a deposit"),
+            TransactionError::NotDisputed => write!(f, "transaction is not under dispute"),
+            TransactionError::IllegalTransition { from, to } => {
+                write!(f, "transaction can't go from {} to {}", from, to)
+            }
+            TransactionError::InvalidTransfer => {
+                write!(f, "transfer has no destination other than its source")
+            }
+            TransactionError::MissingCurrency => {
+                write!(f, "currency or target currency is missing")
+            }
+            TransactionError::SameCurrency => {
+                write!(f, "currency and target currency are the same")
+            }
+            TransactionError::NoRate => write!(f, "no exchange rate between the currencies"),
+            TransactionError::CurrencyMismatch => {
+                write!(f, "currency differs from the referenced transaction")
+            }
+            TransactionError::DisputeWindowClosed => {
+                write!(f, "dispute window of the transaction has closed")
+            }
+            TransactionError::InvalidDisputeAmount => {
+                write!(
+                    f,
+                    "dispute amount is not positive or exceeds the undisputed amount"
+                )
+            }
+            TransactionError::UnknownDispute => {
+                write!(f, "no open dispute of the transaction has this amount")
+            }
+            TransactionError::DuplicateTransaction => {
+                write!(f, "transaction id was already used")
+            }
+            TransactionError::Overflow => write!(f, "balance would overflow"),
+            TransactionError::UnsupportedRecord(record_type) => {
+                write!(f, "{} records cannot be applied to an account", record_type)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+/// Why the engine stopped before applying every record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EngineError {
+    /// The parser went away before sending the end of the input.
+    ParserStopped,
+    /// The parser could not read its input, with the reason.
+    ParserFailed(String),
+    /// A worker thread stopped taking records.
+    WorkerStopped(usize),
+    /// A worker thread panicked, with the panic message.
+    WorkerPanicked(usize, String),
+}
+
+impl std::fmt::Display for EngineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineError::ParserStopped => {
+                write!(f, "the parser stopped before the end of the input")
+            }
+            EngineError::ParserFailed(reason) => write!(f, "the parser failed: {}", reason),
+            EngineError::WorkerStopped(worker) => write!(f, "worker {} stopped", worker),
+            EngineError::WorkerPanicked(worker, message) => {
+                write!(f, "worker {} panicked: {}", worker, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
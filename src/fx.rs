@@ -0,0 +1,260 @@
+use std::{collections::HashMap, path::Path, str::FromStr};
+
+use serde::Deserialize;
+
+use crate::{currency::Currency, money::Money, policy::SECONDS_PER_DAY, record::Timestamp};
+
+const RATE_DECIMALS: usize = 8;
+const RATE_SCALE: i64 = 100_000_000;
+
+/// A day, counted from 1970-01-01.
+pub type Day = i64;
+
+/// Why the rates file could not be loaded.
+#[derive(Debug)]
+pub enum FxError {
+    Csv(csv::Error),
+    /// A row on this line could not be read, with the reason.
+    Malformed(u64, String),
+}
+
+impl std::fmt::Display for FxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FxError::Csv(error) => write!(f, "{}", error),
+            FxError::Malformed(line, message) => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for FxError {}
+
+impl From<csv::Error> for FxError {
+    fn from(error: csv::Error) -> Self {
+        FxError::Csv(error)
+    }
+}
+
+/// An exchange rate with eight decimal places, always positive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rate(i64);
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let malformed = || format!("rate \"{}\" is not a positive decimal number", input);
+        let (whole, fraction) = input.trim().split_once('.').unwrap_or((input.trim(), ""));
+        if whole.is_empty() && fraction.is_empty()
+            || fraction.len() > RATE_DECIMALS
+            || !whole
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|byte| byte.is_ascii_digit())
+        {
+            return Err(malformed());
+        }
+
+        let units = whole
+            .bytes()
+            .chain(fraction.bytes())
+            .chain(std::iter::repeat_n(b'0', RATE_DECIMALS - fraction.len()))
+            .try_fold(0i64, |acc, digit| {
+                acc.checked_mul(10)?.checked_add(i64::from(digit - b'0'))
+            })
+            .filter(|units| *units > 0)
+            .ok_or_else(malformed)?;
+        Ok(Rate(units))
+    }
+}
+
+/// Reads a `YYYY-MM-DD` date as the number of days since 1970-01-01.
+pub fn parse_date(input: &str) -> Result<Day, String> {
+    let malformed = || format!("date \"{}\" is not a valid YYYY-MM-DD date", input);
+    let mut parts = input.trim().splitn(3, '-');
+    let mut next = |digits: usize| {
+        parts
+            .next()
+            .filter(|part| part.len() == digits && part.bytes().all(|byte| byte.is_ascii_digit()))
+            .and_then(|part| part.parse::<i64>().ok())
+            .ok_or_else(malformed)
+    };
+    let (year, month, day) = (next(4)?, next(2)?, next(2)?);
+
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return Err(malformed()),
+    };
+    if !(1..=days_in_month).contains(&day) {
+        return Err(malformed());
+    }
+
+    // days from civil, counting years from March so the leap day comes last
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Ok(era * 146_097 + day_of_era - 719_468)
+}
+
+/// The day a timestamp falls on.
+pub fn day_of(timestamp: Timestamp) -> Day {
+    // a u64 timestamp divided by a day always fits
+    Day::try_from(timestamp / SECONDS_PER_DAY).unwrap_or(Day::MAX)
+}
+
+#[derive(Deserialize)]
+struct RateRow {
+    pair: String,
+    rate: String,
+    date: String,
+}
+
+/// A table of exchange rates by currency pair and effective date.
+///
+/// A rate of `EUR/USD` is the price of one euro in dollars; it also converts dollars to euros.
+/// The rate in effect on a day is the one with the latest effective date on or before it.
+#[derive(Debug, Default)]
+pub struct FxRates {
+    rates: HashMap<(Currency, Currency), Vec<(Day, Rate)>>,
+}
+
+impl FxRates {
+    /// Loads a csv file with a `pair, rate, date` header, e.g. `EUR/PLN, 4.3012, 2024-01-31`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, FxError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(path)?;
+        let headers = reader.headers()?.clone();
+        let mut rates = FxRates::default();
+        let mut fields = csv::StringRecord::new();
+        while reader.read_record(&mut fields)? {
+            let line = fields.position().map_or(0, |position| position.line());
+            let row: RateRow = fields
+                .deserialize(Some(&headers))
+                .map_err(|error| FxError::Malformed(line, error.to_string()))?;
+            let (from, to) = row
+                .pair
+                .split_once('/')
+                .and_then(|(from, to)| Some((from.parse().ok()?, to.parse().ok()?)))
+                .ok_or_else(|| {
+                    FxError::Malformed(
+                        line,
+                        format!("pair \"{}\" is not two currencies like EUR/USD", row.pair),
+                    )
+                })?;
+            let rate = row
+                .rate
+                .parse()
+                .map_err(|message| FxError::Malformed(line, message))?;
+            let day = parse_date(&row.date).map_err(|message| FxError::Malformed(line, message))?;
+            rates.insert(from, to, day, rate);
+        }
+        Ok(rates)
+    }
+
+    /// Adds a rate for `from/to` effective from `day`, replacing one for the same day.
+    pub fn insert(&mut self, from: Currency, to: Currency, day: Day, rate: Rate) {
+        let rates = self.rates.entry((from, to)).or_default();
+        match rates.binary_search_by_key(&day, |(effective, _)| *effective) {
+            Ok(index) => rates[index] = (day, rate),
+            Err(index) => rates.insert(index, (day, rate)),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rates.is_empty()
+    }
+
+    // the rate of a pair in effect on `day`, or the latest one without a day
+    fn rate(&self, from: Currency, to: Currency, day: Option<Day>) -> Option<Rate> {
+        let rates = self.rates.get(&(from, to))?;
+        let effective = match day {
+            Some(day) => rates.partition_point(|(effective, _)| *effective <= day),
+            None => rates.len(),
+        };
+        effective.checked_sub(1).map(|index| rates[index].1)
+    }
+
+    /// Converts `amount` from one currency to another with the rate in effect on `day`, or the
+    /// latest rate without a day. Returns `None` when there is no rate or the result overflows.
+    pub fn convert(
+        &self,
+        amount: Money,
+        from: Currency,
+        to: Currency,
+        day: Option<Day>,
+    ) -> Option<Money> {
+        if from == to {
+            return Some(amount);
+        }
+        if let Some(Rate(rate)) = self.rate(from, to, day) {
+            return amount.checked_mul_ratio(rate, RATE_SCALE);
+        }
+        let Rate(rate) = self.rate(to, from, day)?;
+        amount.checked_mul_ratio(RATE_SCALE, rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn currency(code: &str) -> Currency {
+        code.parse().unwrap()
+    }
+
+    fn money(amount: &str) -> Money {
+        amount.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_dates_as_days_since_epoch() {
+        assert_eq!(Ok(0), parse_date("1970-01-01"));
+        assert_eq!(Ok(-1), parse_date("1969-12-31"));
+        assert_eq!(Ok(19_782), parse_date("2024-02-29"));
+        assert_eq!(Ok(day_of(1_709_251_199)), parse_date("2024-02-29"));
+        for input in ["2023-02-29", "2024-13-01", "2024-1-01", "2024-01-01T00", ""] {
+            assert!(parse_date(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn parses_positive_rates() {
+        assert_eq!(Ok(Rate(430_120_000)), "4.3012".parse());
+        assert_eq!(Ok(Rate(1)), "0.00000001".parse());
+        for input in ["0", "-1", "1.000000001", "", "x"] {
+            assert!(input.parse::<Rate>().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn converts_with_the_rate_in_effect() {
+        let (eur, pln, usd) = (currency("EUR"), currency("PLN"), currency("USD"));
+        let mut rates = FxRates::default();
+        rates.insert(eur, pln, 10, "4".parse().unwrap());
+        rates.insert(eur, pln, 20, "5".parse().unwrap());
+
+        assert_eq!(None, rates.convert(money("1"), eur, pln, Some(9)));
+        assert_eq!(
+            Some(money("4")),
+            rates.convert(money("1"), eur, pln, Some(19))
+        );
+        assert_eq!(
+            Some(money("5")),
+            rates.convert(money("1"), eur, pln, Some(20))
+        );
+        assert_eq!(Some(money("5")), rates.convert(money("1"), eur, pln, None));
+        assert_eq!(
+            Some(money("0.25")),
+            rates.convert(money("1"), pln, eur, Some(10))
+        );
+        assert_eq!(Some(money("7")), rates.convert(money("7"), usd, usd, None));
+        assert_eq!(None, rates.convert(money("1"), eur, usd, None));
+    }
+}
@@ -0,0 +1,51 @@
+//! A small payments engine - reads deposits, withdrawals, disputes, resolves and chargebacks
+//! and keeps the balances of every client account.
+//!
+//! The engine can be embedded directly:
+//!
+//! ```
+//! use transactioner::{Calculator, Record, RecordType};
+//!
+//! let mut calculator = Calculator::new();
+//! calculator
+//!     .calculate(&Record {
+//!         amount: Some("1.5".parse().unwrap()),
+//!         ..Record::new(RecordType::Deposit, 1, 1)
+//!     })
+//!     .unwrap();
+//!
+//! let account = calculator.account(1, None).unwrap();
+//! assert_eq!("1.5000", account.available.to_string());
+//! assert_eq!(1, calculator.accounts().count());
+//! ```
+
+pub mod account;
+pub mod audit;
+pub mod calculator;
+pub mod csvparser;
+pub mod currency;
+pub mod error;
+pub mod fx;
+pub mod money;
+pub mod output;
+pub mod policy;
+pub mod record;
+pub mod rejects;
+pub mod reorder;
+pub mod shard;
+pub mod snapshot;
+
+pub use account::{Account, AccountSnapshot, TransactionState};
+pub use audit::AuditEntry;
+pub use calculator::{Calculator, ConversionError, SortOrder};
+pub use currency::{Currency, CurrencyError};
+pub use error::{EngineError, TransactionError};
+pub use fx::{FxError, FxRates};
+pub use money::{Money, MoneyError};
+pub use output::{AccountSink, CsvSink, JsonLinesSink, JsonSink};
+pub use policy::{NegativeBalances, Policy, WithdrawalDisputes, SECONDS_PER_DAY};
+pub use record::{ClientId, Record, RecordType, Timestamp, TransactionId};
+pub use rejects::{RejectReason, Rejection};
+pub use reorder::ReorderBuffer;
+pub use shard::ShardedCalculator;
+pub use snapshot::{SnapshotError, SNAPSHOT_VERSION};
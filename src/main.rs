@@ -1,38 +1,276 @@
-use std::sync::mpsc::channel;
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{mpsc::sync_channel, Arc},
+};
 
+use clap::Parser;
 use env_logger::Env;
 
-use csvparser::CSVParser;
-use record::Record;
+use cli::{Cli, OutputFormat, EXIT_DATA_ERROR, EXIT_IO_ERROR, EXIT_PIPELINE_ERROR, EXIT_SUCCESS};
+use transactioner::{
+    audit::{AuditEntry, AuditWriter},
+    csvparser::{CSVParser, Message, ParseError},
+    rejects::{Rejection, RejectsWriter},
+    AccountSink, AccountSnapshot, Calculator, CsvSink, FxRates, JsonLinesSink, JsonSink, Policy,
+    ShardedCalculator, SnapshotError, SECONDS_PER_DAY,
+};
 
-mod account;
-mod calculator;
-mod csvparser;
-mod record;
+mod cli;
 
-fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
+fn init_logger(cli: &Cli) {
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or("warn"));
+    if let Some(level) = cli.log_level {
+        builder.filter_level(level);
+    }
+    builder.init();
+}
+
+fn write_rejects(
+    path: &Path,
+    inputs: &[PathBuf],
+    mut rejections: Vec<Rejection>,
+) -> csv::Result<()> {
+    let log_header = "main::write_rejects";
+    log::debug!(
+        "{}: writing {} rejections to {}",
+        log_header,
+        rejections.len(),
+        path.display()
+    );
+    rejections.sort_by_key(|rejection| (rejection.input, rejection.line));
+
+    let mut writer = RejectsWriter::create(path, inputs)?;
+    for rejection in &rejections {
+        writer.write(rejection)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_audit(path: &Path, inputs: &[PathBuf], audit_trail: &[AuditEntry]) -> csv::Result<()> {
+    let log_header = "main::write_audit";
+    log::debug!(
+        "{}: writing {} audit entries to {}",
+        log_header,
+        audit_trail.len(),
+        path.display()
+    );
+
+    let mut writer = AuditWriter::create(path, inputs)?;
+    for entry in audit_trail {
+        writer.write(entry)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_snapshot(path: &Path, calculator: &mut Calculator) -> Result<(), SnapshotError> {
+    let log_header = "main::read_snapshot";
+    log::debug!(
+        "{}: restoring the engine from {}",
+        log_header,
+        path.display()
+    );
+    calculator.restore_snapshot(BufReader::new(File::open(path)?))
+}
+
+// written to a file next to `path` and renamed over it, so a run that fails while writing
+// leaves the previous snapshot as it was
+fn write_snapshot(path: &Path, calculator: &Calculator) -> Result<(), SnapshotError> {
+    let log_header = "main::write_snapshot";
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".tmp");
+    let temp = path.with_file_name(name);
+    log::debug!(
+        "{}: writing the engine state to {} through {}",
+        log_header,
+        path.display(),
+        temp.display()
+    );
+
+    let written = write_snapshot_file(&temp, calculator)
+        .and_then(|()| fs::rename(&temp, path).map_err(SnapshotError::from));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+fn write_snapshot_file(path: &Path, calculator: &Calculator) -> Result<(), SnapshotError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    calculator.write_snapshot(&mut writer)?;
+    let file = writer.into_inner().map_err(|error| error.into_error())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn write_summary(cli: &Cli, accounts: &[AccountSnapshot]) -> io::Result<()> {
+    let writer: Box<dyn Write> = match &cli.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+
+    let mut sink: Box<dyn AccountSink> = match cli.format {
+        // the delimiter is checked to be ascii when the arguments are parsed
+        OutputFormat::Csv => Box::new(CsvSink::new(writer, cli.delimiter as u8)),
+        OutputFormat::Json => Box::new(JsonSink::new(writer)),
+        OutputFormat::Jsonl => Box::new(JsonLinesSink::new(writer)),
+    };
+    for account in accounts {
+        sink.write(account)?;
+    }
+    sink.finish()
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    init_logger(&cli);
     let log_header = "main";
 
-    let (sender, receiver) = channel::<Record>();
+    let policy = Policy {
+        withdrawal_disputes: cli.withdrawal_disputes.into(),
+        negative_balances: cli.negative_balances.into(),
+        dispute_window: cli
+            .dispute_window_days
+            .map(|days| days.saturating_mul(SECONDS_PER_DAY)),
+        hold_period: cli
+            .hold_period_days
+            .map(|days| days.saturating_mul(SECONDS_PER_DAY)),
+    };
+    let rates = match &cli.fx_rates {
+        Some(path) => match FxRates::load(path) {
+            Ok(rates) => rates,
+            Err(error) => {
+                eprintln!("error: could not read {}: {}", path.display(), error);
+                return ExitCode::from(EXIT_IO_ERROR);
+            }
+        },
+        None => FxRates::default(),
+    };
+    let mut calculator = Calculator::with_policy(policy);
+    calculator.set_rates(Arc::new(rates));
+    for operator in &cli.operators {
+        calculator.authorize(operator.clone());
+    }
+    if let Some(path) = &cli.restore {
+        if let Err(error) = read_snapshot(path, &mut calculator) {
+            eprintln!("error: could not read {}: {}", path.display(), error);
+            return ExitCode::from(EXIT_IO_ERROR);
+        }
+    }
+    let workers = usize::from(cli.workers);
+    // both are at most u32::MAX, which fits a usize on every supported target
+    let capacity = cli.channel_capacity as usize;
+    let batch_size = cli.batch_size as usize;
+    let (sender, receiver) = sync_channel::<Message>(capacity);
 
     let join_thread = std::thread::spawn(move || {
         log::debug!(
-            "{}::thread: creating new Engine and calling run on it",
-            log_header
+            "{}::thread: creating new Engine with {} workers and calling run on it",
+            log_header,
+            workers
         );
-        calculator::Calculator::new(receiver).run();
+        ShardedCalculator::from_calculator(workers, capacity, batch_size, calculator).run(receiver)
     });
 
     log::debug!(
         "{}: creating inplace a new CSVReader and calling read_file on it",
         log_header
     );
-    CSVParser::new(sender).parse_records();
+    let mut parser = CSVParser::new(sender, &cli.inputs);
+    parser.batch_size(batch_size);
+    if let Some(lateness) = cli.lateness {
+        parser.reorder_by_timestamp(lateness);
+    }
+    let parsed = parser.parse_records();
+    let metrics = parser.metrics();
+    // the engine stops once the sender is gone, even when the parse stopped early
+    drop(parser);
+    log::info!(
+        "{}: sent {} records in {} batches, waited on a full channel {} times for {:?}",
+        log_header,
+        metrics.records,
+        metrics.batches,
+        metrics.blocked_sends,
+        metrics.blocked
+    );
 
     log::debug!(
         "{}: calling join_thread on the created thread, will wait for Engine to finish processing",
         log_header
     );
-    let _ = join_thread.join();
+    let engine = join_thread.join();
+
+    // an unreadable input stops the engine too, so the parser error is the one to report
+    let mut rejections = match parsed {
+        Ok(rejections) => rejections,
+        Err(ParseError::Input(error)) => {
+            eprintln!("error: the parser stage failed: {}", error);
+            return ExitCode::from(EXIT_IO_ERROR);
+        }
+        Err(error @ ParseError::EngineStopped) => {
+            log::debug!("{}: parser error == {}", log_header, error);
+            Vec::new()
+        }
+    };
+    let (calculator, refused) = match engine {
+        Ok(Ok(engine)) => engine,
+        Ok(Err(error)) => {
+            eprintln!("error: the engine stage failed: {}", error);
+            return ExitCode::from(EXIT_PIPELINE_ERROR);
+        }
+        Err(_) => {
+            eprintln!("error: the engine stage failed: the engine thread panicked");
+            return ExitCode::from(EXIT_PIPELINE_ERROR);
+        }
+    };
+    rejections.extend(refused);
+    let rejected = rejections.len();
+
+    if let Some(path) = &cli.rejects {
+        if let Err(error) = write_rejects(path, &cli.inputs, rejections) {
+            eprintln!("error: could not write {}: {}", path.display(), error);
+            return ExitCode::from(EXIT_IO_ERROR);
+        }
+    }
+
+    if let Some(path) = &cli.audit {
+        if let Err(error) = write_audit(path, &cli.inputs, calculator.audit_trail()) {
+            eprintln!("error: could not write {}: {}", path.display(), error);
+            return ExitCode::from(EXIT_IO_ERROR);
+        }
+    }
+
+    if cli.strict && rejected > 0 {
+        eprintln!("error: {} rows were rejected, no summary written", rejected);
+        return ExitCode::from(EXIT_DATA_ERROR);
+    }
+
+    let accounts = match cli.reporting_currency {
+        Some(currency) => match calculator.consolidated_accounts(cli.sort.into(), currency) {
+            Ok(accounts) => accounts,
+            Err(error) => {
+                eprintln!("error: {}, no summary written", error);
+                return ExitCode::from(EXIT_DATA_ERROR);
+            }
+        },
+        None => calculator.sorted_accounts(cli.sort.into()),
+    };
+    if let Err(error) = write_summary(&cli, &accounts) {
+        eprintln!("error: could not write the account summary: {}", error);
+        return ExitCode::from(EXIT_IO_ERROR);
+    }
+
+    if let Some(path) = &cli.snapshot {
+        if let Err(error) = write_snapshot(path, &calculator) {
+            eprintln!("error: could not write {}: {}", path.display(), error);
+            return ExitCode::from(EXIT_IO_ERROR);
+        }
+    }
+
+    ExitCode::from(EXIT_SUCCESS)
 }
@@ -0,0 +1,221 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const DECIMALS: usize = 4;
+const SCALE: i64 = 10_000;
+
+/// Why a string could not be read as `Money`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MoneyError {
+    Empty,
+    Malformed,
+    TooManyDecimals,
+    Overflow,
+}
+
+impl std::fmt::Display for MoneyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MoneyError::Empty => write!(f, "amount is empty"),
+            MoneyError::Malformed => write!(f, "amount is not a decimal number"),
+            MoneyError::TooManyDecimals => {
+                write!(f, "amount has more than {} decimal places", DECIMALS)
+            }
+            MoneyError::Overflow => write!(f, "amount is too large"),
+        }
+    }
+}
+
+impl std::error::Error for MoneyError {}
+
+/// A fixed-point amount with four decimal places, stored as ten-thousandths of a unit.
+///
+/// Arithmetic is checked - an operation that does not fit returns `None` instead of wrapping.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        self.0.checked_add(other.0).map(Money)
+    }
+
+    pub fn checked_sub(self, other: Money) -> Option<Money> {
+        self.0.checked_sub(other.0).map(Money)
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// Multiplies by `numerator / denominator`, rounding half away from zero to four decimal
+    /// places. Returns `None` when the result does not fit or the denominator is zero.
+    pub fn checked_mul_ratio(self, numerator: i64, denominator: i64) -> Option<Money> {
+        if denominator == 0 {
+            return None;
+        }
+        let product = i128::from(self.0) * i128::from(numerator);
+        let denominator = i128::from(denominator);
+        let quotient = product / denominator;
+        let remainder = product % denominator;
+        let rounded = if 2 * remainder.abs() >= denominator.abs() {
+            quotient + product.signum() * denominator.signum()
+        } else {
+            quotient
+        };
+        i64::try_from(rounded).ok().map(Money)
+    }
+}
+
+impl FromStr for Money {
+    type Err = MoneyError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+        if input.is_empty() {
+            return Err(MoneyError::Empty);
+        }
+
+        let (negative, unsigned) = match input.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, input.strip_prefix('+').unwrap_or(input)),
+        };
+        let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+
+        if whole.is_empty() && fraction.is_empty()
+            || !whole.bytes().all(|byte| byte.is_ascii_digit())
+            || !fraction.bytes().all(|byte| byte.is_ascii_digit())
+        {
+            return Err(MoneyError::Malformed);
+        }
+        if fraction.len() > DECIMALS {
+            return Err(MoneyError::TooManyDecimals);
+        }
+
+        let whole = whole
+            .bytes()
+            .try_fold(0i64, |acc, digit| {
+                acc.checked_mul(10)?.checked_add(i64::from(digit - b'0'))
+            })
+            .ok_or(MoneyError::Overflow)?;
+        let fraction = fraction
+            .bytes()
+            .chain(std::iter::repeat(b'0'))
+            .take(DECIMALS)
+            .fold(0i64, |acc, digit| acc * 10 + i64::from(digit - b'0'));
+
+        let value = whole
+            .checked_mul(SCALE)
+            .and_then(|scaled| scaled.checked_add(fraction))
+            .ok_or(MoneyError::Overflow)?;
+
+        Ok(Money(if negative { -value } else { value }))
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let input = String::deserialize(deserializer)?;
+        input.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// serialized as a string so json consumers never see a rounded float
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl std::fmt::Display for Money {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let value = self.0.unsigned_abs();
+        let scale = SCALE.unsigned_abs();
+        write!(
+            f,
+            "{}{}.{:0width$}",
+            sign,
+            value / scale,
+            value % scale,
+            width = DECIMALS
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display_round_trip() {
+        for (input, expected) in [
+            ("1", "1.0000"),
+            ("1.0", "1.0000"),
+            ("0.1337", "0.1337"),
+            (".5", "0.5000"),
+            ("-2.25", "-2.2500"),
+            ("+3", "3.0000"),
+            ("-0.0001", "-0.0001"),
+        ] {
+            let money: Money = input.parse().unwrap();
+            assert_eq!(expected, money.to_string());
+        }
+    }
+
+    #[test]
+    fn parse_rejects_more_than_four_decimals() {
+        assert_eq!(Err(MoneyError::TooManyDecimals), "0.13370".parse::<Money>());
+        assert_eq!(Err(MoneyError::TooManyDecimals), "1.00001".parse::<Money>());
+    }
+
+    #[test]
+    fn parse_rejects_malformed_input() {
+        for input in [".", "-", "1.2.3", "abc", "1e5", "1,5", "- 1"] {
+            assert_eq!(Err(MoneyError::Malformed), input.parse::<Money>());
+        }
+        assert_eq!(Err(MoneyError::Empty), "".parse::<Money>());
+    }
+
+    #[test]
+    fn parse_reports_overflow() {
+        assert_eq!(
+            Err(MoneyError::Overflow),
+            "922337203685478".parse::<Money>()
+        );
+        assert!("922337203685477.5807".parse::<Money>().is_ok());
+    }
+
+    #[test]
+    fn repeated_additions_do_not_drift() {
+        let step: Money = "0.1337".parse().unwrap();
+        let mut total = Money::ZERO;
+        for _ in 0..1_000_000 {
+            total = total.checked_add(step).unwrap();
+        }
+        assert_eq!("133700.0000", total.to_string());
+    }
+
+    #[test]
+    fn checked_arithmetic_reports_overflow() {
+        let max: Money = "922337203685477.5807".parse().unwrap();
+        let tiny: Money = "0.0001".parse().unwrap();
+
+        assert_eq!(None, max.checked_add(tiny));
+        assert_eq!(Some(Money::ZERO), tiny.checked_sub(tiny));
+    }
+
+    #[test]
+    fn multiplies_by_a_ratio_with_rounding() {
+        let money = |amount: &str| amount.parse::<Money>().unwrap();
+
+        assert_eq!(Some(money("4.3")), money("1").checked_mul_ratio(43, 10));
+        assert_eq!(Some(money("0.3333")), money("1").checked_mul_ratio(1, 3));
+        assert_eq!(Some(money("0.6667")), money("2").checked_mul_ratio(1, 3));
+        assert_eq!(Some(money("-0.6667")), money("-2").checked_mul_ratio(1, 3));
+        assert_eq!(None, money("1").checked_mul_ratio(1, 0));
+        assert_eq!(None, money("1").checked_mul_ratio(i64::MAX, 1));
+    }
+}
@@ -0,0 +1,189 @@
+use std::io::{self, Write};
+
+use crate::account::AccountSnapshot;
+
+/// A destination for the account summary; every output format takes the same snapshots.
+pub trait AccountSink {
+    fn write(&mut self, account: &AccountSnapshot) -> io::Result<()>;
+
+    /// Completes the output and flushes the underlying writer, must be called once at the end.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// `client,currency,available,held,total,locked,receivable,overdrawn` rows with a header line.
+pub struct CsvSink<W: Write> {
+    writer: csv::Writer<W>,
+    empty: bool,
+}
+
+impl<W: Write> CsvSink<W> {
+    // the header `serialize` writes before the first row, written by hand when there is none
+    const HEADER: [&'static str; 8] = [
+        "client",
+        "currency",
+        "available",
+        "held",
+        "total",
+        "locked",
+        "receivable",
+        "overdrawn",
+    ];
+
+    pub fn new(writer: W, delimiter: u8) -> Self {
+        Self {
+            writer: csv::WriterBuilder::new()
+                .delimiter(delimiter)
+                .from_writer(writer),
+            empty: true,
+        }
+    }
+}
+
+impl<W: Write> AccountSink for CsvSink<W> {
+    fn write(&mut self, account: &AccountSnapshot) -> io::Result<()> {
+        self.empty = false;
+        Ok(self.writer.serialize(account)?)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.empty {
+            self.writer.write_record(Self::HEADER)?;
+        }
+        self.writer.flush()
+    }
+}
+
+/// A single json array of account objects.
+pub struct JsonSink<W: Write> {
+    writer: W,
+    empty: bool,
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            empty: true,
+        }
+    }
+}
+
+impl<W: Write> AccountSink for JsonSink<W> {
+    fn write(&mut self, account: &AccountSnapshot) -> io::Result<()> {
+        self.writer
+            .write_all(if self.empty { b"[" } else { b"," })?;
+        self.empty = false;
+        serde_json::to_writer(&mut self.writer, account)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer
+            .write_all(if self.empty { b"[]\n" } else { b"]\n" })?;
+        self.writer.flush()
+    }
+}
+
+/// One json account object per line.
+pub struct JsonLinesSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonLinesSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write> AccountSink for JsonLinesSink<W> {
+    fn write(&mut self, account: &AccountSnapshot) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, account)?;
+        self.writer.write_all(b"\n")
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::Money;
+
+    fn snapshots() -> Vec<AccountSnapshot> {
+        vec![
+            AccountSnapshot {
+                client_id: 1,
+                currency: None,
+                available: "1.5".parse().unwrap(),
+                held: Money::ZERO,
+                total: "1.5".parse().unwrap(),
+                locked: false,
+                receivable: Money::ZERO,
+                overdrawn: false,
+            },
+            AccountSnapshot {
+                client_id: 2,
+                currency: Some("EUR".parse().unwrap()),
+                available: "-2".parse().unwrap(),
+                held: "0.0001".parse().unwrap(),
+                total: "-1.9999".parse().unwrap(),
+                locked: true,
+                receivable: "0.5".parse().unwrap(),
+                overdrawn: true,
+            },
+        ]
+    }
+
+    fn render<S: AccountSink>(mut sink: S, accounts: &[AccountSnapshot]) -> S {
+        for account in accounts {
+            sink.write(account).unwrap();
+        }
+        sink.finish().unwrap();
+        sink
+    }
+
+    #[test]
+    fn csv_sink_writes_header_and_rows_with_delimiter() {
+        let sink = render(CsvSink::new(Vec::new(), b';'), &snapshots());
+        let output = String::from_utf8(sink.writer.into_inner().unwrap()).unwrap();
+
+        assert_eq!(
+            "client;currency;available;held;total;locked;receivable;overdrawn\n\
+             1;;1.5000;0.0000;1.5000;false;0.0000;false\n\
+             2;EUR;-2.0000;0.0001;-1.9999;true;0.5000;true\n",
+            output
+        );
+
+        let empty = render(CsvSink::new(Vec::new(), b','), &[]);
+        assert_eq!(
+            "client,currency,available,held,total,locked,receivable,overdrawn\n",
+            String::from_utf8(empty.writer.into_inner().unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn json_sink_writes_an_array() {
+        let sink = render(JsonSink::new(Vec::new()), &snapshots());
+
+        assert_eq!(
+            "[{\"client\":1,\"currency\":null,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false,\"receivable\":\"0.0000\",\"overdrawn\":false},\
+             {\"client\":2,\"currency\":\"EUR\",\"available\":\"-2.0000\",\"held\":\"0.0001\",\"total\":\"-1.9999\",\"locked\":true,\"receivable\":\"0.5000\",\"overdrawn\":true}]\n",
+            String::from_utf8(sink.writer).unwrap()
+        );
+
+        let empty = render(JsonSink::new(Vec::new()), &[]);
+        assert_eq!("[]\n", String::from_utf8(empty.writer).unwrap());
+    }
+
+    #[test]
+    fn json_lines_sink_writes_an_object_per_line() {
+        let sink = render(JsonLinesSink::new(Vec::new()), &snapshots()[..1]);
+
+        assert_eq!(
+            "{\"client\":1,\"currency\":null,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false,\"receivable\":\"0.0000\",\"overdrawn\":false}\n",
+            String::from_utf8(sink.writer).unwrap()
+        );
+    }
+}
@@ -0,0 +1,39 @@
+use crate::record::Timestamp;
+
+/// Seconds in a day, the unit time limits are usually given in.
+pub const SECONDS_PER_DAY: Timestamp = 24 * 60 * 60;
+
+/// How a dispute on a withdrawal is settled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WithdrawalDisputes {
+    /// Only deposits can be disputed, a dispute on a withdrawal is refused.
+    #[default]
+    Reject,
+    /// The withdrawn amount is held while disputed; a resolve drops the hold and a chargeback
+    /// credits the amount back to available funds and locks the account.
+    ReversedHold,
+}
+
+/// What happens when a dispute on a deposit asks to hold more than the available funds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NegativeBalances {
+    /// The whole amount is held and available funds go negative.
+    #[default]
+    Allow,
+    /// The dispute is refused.
+    Reject,
+    /// Only the available funds are held, the rest is recorded as a receivable the client owes.
+    Cap,
+}
+
+/// Business rules an `Account` applies when settling disputes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Policy {
+    pub withdrawal_disputes: WithdrawalDisputes,
+    pub negative_balances: NegativeBalances,
+    /// Seconds after a transaction during which it can be disputed, `None` for no limit.
+    pub dispute_window: Option<Timestamp>,
+    /// Seconds after which an open dispute is resolved when no resolve or chargeback arrived,
+    /// `None` to keep disputes open until settled.
+    pub hold_period: Option<Timestamp>,
+}
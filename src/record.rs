@@ -1,6 +1,28 @@
-use serde::Deserialize;
+use std::fmt::Display;
 
-#[derive(Deserialize, PartialEq, Eq)]
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{currency::Currency, money::Money};
+
+/// Identifier of a client account, `u32` with the `wide-client-ids` feature.
+#[cfg(not(feature = "wide-client-ids"))]
+pub type ClientId = u16;
+/// Identifier of a client account, `u16` without the `wide-client-ids` feature.
+#[cfg(feature = "wide-client-ids")]
+pub type ClientId = u32;
+
+/// Identifier of a transaction, `u64` with the `wide-transaction-ids` feature.
+#[cfg(not(feature = "wide-transaction-ids"))]
+pub type TransactionId = u32;
+/// Identifier of a transaction, `u32` without the `wide-transaction-ids` feature.
+#[cfg(feature = "wide-transaction-ids")]
+pub type TransactionId = u64;
+
+/// A point in time, in seconds since the unix epoch.
+pub type Timestamp = u64;
+
+/// The kind of operation a `Record` describes.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum RecordType {
     Deposit,
@@ -8,33 +30,193 @@ pub enum RecordType {
     Dispute,
     Resolve,
     Chargeback,
-    #[serde(skip_deserializing)]
-    Invalid,
-    #[serde(skip_deserializing)]
-    Finished,
+    /// Moves funds from the `currency` balance to the `to_currency` balance.
+    Convert,
+    /// Moves funds from the account of `client` to the account of `to_client`.
+    Transfer,
+    /// Lifts the lock of an account, administrative.
+    Unlock,
+    /// Locks an account, administrative.
+    Freeze,
+    /// Locks an account for good, administrative.
+    Close,
 }
 
-#[derive(Deserialize)]
+/// A single input row; `authorization` is only used by administrative records.
+#[derive(Clone, Debug, Deserialize)]
 pub struct Record {
     #[serde(rename = "type")]
     pub record_type: RecordType,
-    #[serde(rename = "client")]
-    pub client_id: u16,
-    #[serde(rename = "tx")]
-    pub trx_id: u16,
+    #[serde(rename = "client", deserialize_with = "deserialize_client_id")]
+    pub client_id: ClientId,
+    #[serde(rename = "tx", deserialize_with = "deserialize_trx_id")]
+    pub trx_id: TransactionId,
+    /// The amount a deposit, withdrawal, conversion or transfer moves. On a dispute it is the
+    /// part of the transaction to dispute, on a resolve or chargeback it picks the dispute to
+    /// settle; without it a dispute takes the whole undisputed rest and a settlement the oldest
+    /// open dispute.
     #[serde(rename = "amount")]
-    pub amount: Option<f64>,
+    pub amount: Option<Money>,
+    /// Currency of a deposit or withdrawal, records without one share a balance of their own.
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// The currency a conversion moves funds to.
+    #[serde(default)]
+    pub to_currency: Option<Currency>,
+    /// The client a transfer moves funds to.
+    #[serde(default, deserialize_with = "deserialize_to_client_id")]
+    pub to_client: Option<ClientId>,
+    /// The operator that authorized an administrative record.
+    #[serde(default)]
+    pub authorization: Option<String>,
+    /// When the record happened, records without one are never checked against time limits.
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+    /// Index of the input the record was read from, in the order the inputs were given.
+    #[serde(skip)]
+    pub input: usize,
+    /// Line of the input the record was read from, 0 when it did not come from a file.
+    #[serde(skip)]
+    pub line: u64,
 }
 
 impl Record {
-    pub fn default() -> Self {
+    /// Names of the input fields, in the order `Record::fields` writes them.
+    pub const FIELDS: [&'static str; 9] = [
+        "type",
+        "client",
+        "tx",
+        "amount",
+        "currency",
+        "to_currency",
+        "to_client",
+        "timestamp",
+        "authorization",
+    ];
+
+    /// A record of `record_type` for a client and transaction, with every optional field empty,
+    /// as if it were read from line 0 of the first input.
+    pub fn new(record_type: RecordType, client_id: ClientId, trx_id: TransactionId) -> Self {
         Self {
-            record_type: RecordType::Invalid,
-            client_id: u16::MAX,
-            trx_id: u16::MAX,
+            record_type,
+            client_id,
+            trx_id,
             amount: None,
+            currency: None,
+            to_currency: None,
+            to_client: None,
+            authorization: None,
+            timestamp: None,
+            input: 0,
+            line: 0,
         }
     }
+
+    /// The record written back as input fields, in the order of `Record::FIELDS`.
+    pub fn fields(&self) -> Vec<String> {
+        fn optional<T: Display>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map(|value| value.to_string())
+                .unwrap_or_default()
+        }
+
+        vec![
+            self.record_type.name().to_string(),
+            self.client_id.to_string(),
+            self.trx_id.to_string(),
+            optional(&self.amount),
+            optional(&self.currency),
+            optional(&self.to_currency),
+            optional(&self.to_client),
+            optional(&self.timestamp),
+            optional(&self.authorization),
+        ]
+    }
+}
+
+// ids are read as the widest integer first so an out of range id gets a readable error
+fn deserialize_id<'de, D, T>(deserializer: D, name: &str, max: T) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64> + Display,
+{
+    let id = u64::deserialize(deserializer)?;
+    id_in_range(id, name, max)
+}
+
+fn id_in_range<E, T>(id: u64, name: &str, max: T) -> Result<T, E>
+where
+    E: serde::de::Error,
+    T: TryFrom<u64> + Display,
+{
+    T::try_from(id).map_err(|_| {
+        E::custom(format!(
+            "{} id {} is out of range, the maximum is {}",
+            name, id, max
+        ))
+    })
+}
+
+fn deserialize_client_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ClientId, D::Error> {
+    deserialize_id(deserializer, "client", ClientId::MAX)
+}
+
+fn deserialize_to_client_id<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<ClientId>, D::Error> {
+    Option::<u64>::deserialize(deserializer)?
+        .map(|id| id_in_range(id, "client", ClientId::MAX))
+        .transpose()
+}
+
+fn deserialize_trx_id<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<TransactionId, D::Error> {
+    deserialize_id(deserializer, "transaction", TransactionId::MAX)
+}
+
+impl RecordType {
+    /// The name used for this type in the input.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordType::Deposit => "deposit",
+            RecordType::Withdrawal => "withdrawal",
+            RecordType::Dispute => "dispute",
+            RecordType::Resolve => "resolve",
+            RecordType::Chargeback => "chargeback",
+            RecordType::Convert => "convert",
+            RecordType::Transfer => "transfer",
+            RecordType::Unlock => "unlock",
+            RecordType::Freeze => "freeze",
+            RecordType::Close => "close",
+        }
+    }
+
+    /// Whether this is an administrative record that needs an authorization.
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            RecordType::Unlock | RecordType::Freeze | RecordType::Close
+        )
+    }
+
+    /// Whether a record of this type stores a transaction under its id, which no other record
+    /// of such a type may use again.
+    pub fn takes_id(&self) -> bool {
+        matches!(
+            self,
+            RecordType::Deposit
+                | RecordType::Withdrawal
+                | RecordType::Convert
+                | RecordType::Transfer
+        )
+    }
+
+    /// Whether a successful record of this type changes the lock state of its account.
+    pub fn changes_lock(&self) -> bool {
+        self.is_admin() || *self == RecordType::Chargeback
+    }
 }
 
 impl std::fmt::Display for RecordType {
@@ -45,8 +227,11 @@ impl std::fmt::Display for RecordType {
             RecordType::Dispute => write!(f, "Dispute"),
             RecordType::Resolve => write!(f, "Resolve"),
             RecordType::Chargeback => write!(f, "Chargeback"),
-            RecordType::Invalid => write!(f, "Invalid"),
-            RecordType::Finished => write!(f, "Finished"),
+            RecordType::Convert => write!(f, "Convert"),
+            RecordType::Transfer => write!(f, "Transfer"),
+            RecordType::Unlock => write!(f, "Unlock"),
+            RecordType::Freeze => write!(f, "Freeze"),
+            RecordType::Close => write!(f, "Close"),
         }
     }
 }
@@ -55,8 +240,63 @@ impl std::fmt::Display for Record {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{}, {:?}, {:?}, {:?}",
-            self.record_type, self.client_id, self.trx_id, self.amount
-        )
+            "{}, {:?}, {:?}, ",
+            self.record_type, self.client_id, self.trx_id
+        )?;
+        match self.amount {
+            Some(amount) => write!(f, "{}", amount),
+            None => write!(f, "None"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(row: &str) -> Result<Record, String> {
+        let input = format!("type,client,tx,amount,to_client\n{}\n", row);
+        let mut reader = csv::Reader::from_reader(input.as_bytes());
+        let record = reader.deserialize::<Record>().next().unwrap();
+        record.map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn deserialize_reads_ids_up_to_their_maximum() {
+        let record = read(&format!(
+            "transfer,{},{},1,{}",
+            ClientId::MAX,
+            TransactionId::MAX,
+            ClientId::MAX
+        ))
+        .unwrap();
+
+        assert_eq!(ClientId::MAX, record.client_id);
+        assert_eq!(TransactionId::MAX, record.trx_id);
+        assert_eq!(Some(ClientId::MAX), record.to_client);
+    }
+
+    #[test]
+    #[cfg(not(feature = "wide-client-ids"))]
+    fn deserialize_refuses_a_client_id_out_of_range() {
+        for row in ["deposit,70000,1,1,", "transfer,1,1,1,70000"] {
+            let error = read(row).unwrap_err();
+            assert!(
+                error.ends_with("client id 70000 is out of range, the maximum is 65535"),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "wide-transaction-ids"))]
+    fn deserialize_refuses_a_transaction_id_out_of_range() {
+        let error = read("deposit,1,4294967296,1,").unwrap_err();
+        assert!(
+            error.ends_with("transaction id 4294967296 is out of range, the maximum is 4294967295"),
+            "{}",
+            error
+        );
     }
 }
@@ -0,0 +1,190 @@
+use std::{fs::File, io::Write, path::Path};
+
+use crate::{error::TransactionError, record::Record};
+
+/// Why an input row did not make it into the account summary.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The row could not be read as a `Record`; holds the parser message.
+    Malformed(String),
+    /// The row was read but an account refused it.
+    Refused(TransactionError),
+    /// The row came after the lateness window and could not be put in timestamp order.
+    TooLate,
+}
+
+impl RejectReason {
+    /// A stable, machine-readable name of the reason.
+    pub fn code(&self) -> &'static str {
+        match self {
+            RejectReason::Malformed(_) => "malformed_record",
+            RejectReason::Refused(error) => error.code(),
+            RejectReason::TooLate => "too_late",
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::Malformed(message) => write!(f, "{}", message),
+            RejectReason::Refused(error) => write!(f, "{}", error),
+            RejectReason::TooLate => write!(f, "record arrived after the lateness window"),
+        }
+    }
+}
+
+/// A single refused input row together with the fields it was read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    pub input: usize,
+    pub line: u64,
+    pub fields: Vec<String>,
+    pub reason: RejectReason,
+}
+
+impl Rejection {
+    pub fn refused(record: &Record, error: TransactionError) -> Self {
+        Self {
+            input: record.input,
+            line: record.line,
+            fields: record.fields(),
+            reason: RejectReason::Refused(error),
+        }
+    }
+
+    pub fn too_late(record: &Record) -> Self {
+        Self {
+            input: record.input,
+            line: record.line,
+            fields: record.fields(),
+            reason: RejectReason::TooLate,
+        }
+    }
+}
+
+/// Writes rejections as csv - `input, line`, the fields of `Record::FIELDS`, `reason, detail`.
+pub struct RejectsWriter<W: Write> {
+    writer: csv::Writer<W>,
+    inputs: Vec<String>,
+}
+
+impl RejectsWriter<File> {
+    /// `inputs` are the names of the inputs that `Rejection::input` indexes into.
+    pub fn create<P: AsRef<Path>, I: AsRef<Path>>(path: P, inputs: &[I]) -> csv::Result<Self> {
+        Self::new(File::create(path)?, inputs)
+    }
+}
+
+impl<W: Write> RejectsWriter<W> {
+    /// `inputs` are the names of the inputs that `Rejection::input` indexes into.
+    pub fn new<I: AsRef<Path>>(writer: W, inputs: &[I]) -> csv::Result<Self> {
+        let mut writer = csv::Writer::from_writer(writer);
+        writer.write_record(
+            ["input", "line"]
+                .into_iter()
+                .chain(Record::FIELDS)
+                .chain(["reason", "detail"]),
+        )?;
+        Ok(Self {
+            writer,
+            inputs: inputs
+                .iter()
+                .map(|input| input.as_ref().display().to_string())
+                .collect(),
+        })
+    }
+
+    pub fn write(&mut self, rejection: &Rejection) -> csv::Result<()> {
+        let input = self.inputs.get(rejection.input).map_or("", String::as_str);
+        let line = rejection.line.to_string();
+        // malformed rows may have any number of fields, keep the column layout fixed
+        let fields = rejection
+            .fields
+            .iter()
+            .map(String::as_str)
+            .chain(std::iter::repeat(""))
+            .take(Record::FIELDS.len());
+        let detail = rejection.reason.to_string();
+
+        self.writer.write_record(
+            [input, line.as_str()]
+                .into_iter()
+                .chain(fields)
+                .chain([rejection.reason.code(), detail.as_str()]),
+        )
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::RecordType;
+
+    #[test]
+    fn refused_keeps_every_field_of_the_record() {
+        let record = Record {
+            amount: Some("3".parse().unwrap()),
+            currency: Some("EUR".parse().unwrap()),
+            to_client: Some(4),
+            timestamp: Some(5),
+            ..Record::new(RecordType::Transfer, 1, 2)
+        };
+
+        let rejection = Rejection::refused(&record, TransactionError::InsufficientFunds);
+
+        assert_eq!(Record::FIELDS.len(), rejection.fields.len());
+        assert_eq!(
+            vec!["transfer", "1", "2", "3.0000", "EUR", "", "4", "5", ""],
+            rejection.fields
+        );
+    }
+
+    fn written(rejections: &[Rejection]) -> Vec<String> {
+        let mut output = Vec::new();
+        let mut writer = RejectsWriter::new(&mut output, &["in.csv"]).unwrap();
+        for rejection in rejections {
+            writer.write(rejection).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    fn malformed(fields: &[&str]) -> Rejection {
+        Rejection {
+            input: 0,
+            line: 2,
+            fields: fields.iter().map(|field| field.to_string()).collect(),
+            reason: RejectReason::Malformed("bad".to_string()),
+        }
+    }
+
+    #[test]
+    fn write_pads_and_truncates_the_fields_to_a_fixed_layout() {
+        let lines = written(&[
+            malformed(&[]),
+            malformed(&["deposit", "1"]),
+            malformed(&["deposit", "1", "2", "3", "", "", "", "4", "op", "extra"]),
+        ]);
+
+        assert_eq!(
+            vec![
+                "input,line,type,client,tx,amount,currency,to_currency,to_client,timestamp,\
+                 authorization,reason,detail",
+                "in.csv,2,,,,,,,,,,malformed_record,bad",
+                "in.csv,2,deposit,1,,,,,,,,malformed_record,bad",
+                "in.csv,2,deposit,1,2,3,,,,4,op,malformed_record,bad",
+            ],
+            lines
+        );
+    }
+}
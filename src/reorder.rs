@@ -0,0 +1,165 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use crate::record::{Record, Timestamp};
+
+// a buffered record, ordered by timestamp and then by arrival
+struct Pending {
+    timestamp: Timestamp,
+    sequence: u64,
+    record: Record,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        (self.timestamp, self.sequence) == (other.timestamp, other.sequence)
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.timestamp, self.sequence).cmp(&(other.timestamp, other.sequence))
+    }
+}
+
+/// Puts records that arrive slightly out of order back in timestamp order.
+///
+/// A record is held back until a record more than `lateness` seconds newer arrives, so it can
+/// still be overtaken by anything older that is at most `lateness` behind the newest record.
+/// A record older than one that was already released is too late and is handed back.
+/// Records without a timestamp are ordered as if they had the newest timestamp seen so far.
+pub struct ReorderBuffer {
+    lateness: Timestamp,
+    newest: Option<Timestamp>,
+    released: Option<Timestamp>,
+    sequence: u64,
+    pending: BinaryHeap<Reverse<Pending>>,
+}
+
+impl ReorderBuffer {
+    pub fn new(lateness: Timestamp) -> Self {
+        Self {
+            lateness,
+            newest: None,
+            released: None,
+            sequence: 0,
+            pending: BinaryHeap::new(),
+        }
+    }
+
+    /// Buffers a record, or returns it when it is too late to be put in order.
+    pub fn push(&mut self, record: Record) -> Result<(), Record> {
+        let timestamp = match record.timestamp {
+            Some(timestamp) => timestamp,
+            None => self.newest.or(self.released).unwrap_or_default(),
+        };
+        if self.released.is_some_and(|released| timestamp < released) {
+            return Err(record);
+        }
+
+        self.newest = self.newest.max(Some(timestamp));
+        self.sequence += 1;
+        self.pending.push(Reverse(Pending {
+            timestamp,
+            sequence: self.sequence,
+            record,
+        }));
+        Ok(())
+    }
+
+    /// Removes the oldest record that can no longer be overtaken, if any.
+    pub fn pop_ready(&mut self) -> Option<Record> {
+        let watermark = self.newest?.saturating_sub(self.lateness);
+        match self.pending.peek() {
+            Some(Reverse(pending)) if pending.timestamp <= watermark => self.pop(),
+            _ => None,
+        }
+    }
+
+    /// Removes the oldest buffered record regardless of the lateness, used once no more
+    /// records will arrive.
+    pub fn pop(&mut self) -> Option<Record> {
+        let Reverse(pending) = self.pending.pop()?;
+        self.released = Some(pending.timestamp);
+        Some(pending.record)
+    }
+
+    /// Number of records waiting in the buffer.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::{RecordType, TransactionId};
+
+    fn record(trx_id: TransactionId, timestamp: Option<Timestamp>) -> Record {
+        Record {
+            timestamp,
+            ..Record::new(RecordType::Deposit, 1, trx_id)
+        }
+    }
+
+    fn trx_ids(records: impl IntoIterator<Item = Record>) -> Vec<TransactionId> {
+        records.into_iter().map(|record| record.trx_id).collect()
+    }
+
+    #[test]
+    fn releases_records_in_timestamp_order_after_the_lateness() {
+        let mut buffer = ReorderBuffer::new(10);
+        let mut released = Vec::new();
+
+        for (trx_id, timestamp) in [(1, 100), (2, 95), (3, 105), (4, 111), (5, 120)] {
+            assert!(buffer.push(record(trx_id, Some(timestamp))).is_ok());
+            while let Some(record) = buffer.pop_ready() {
+                released.push(record);
+            }
+        }
+        assert_eq!(vec![2, 1, 3], trx_ids(released.drain(..)));
+        assert_eq!(2, buffer.len());
+
+        // 106 arrived behind 111 and 120 but is still in time, 104 is older than the released 105
+        assert!(buffer.push(record(6, Some(106))).is_ok());
+        assert_eq!(
+            Some(7),
+            buffer
+                .push(record(7, Some(104)))
+                .err()
+                .map(|record| record.trx_id)
+        );
+
+        while let Some(record) = buffer.pop() {
+            released.push(record);
+        }
+        assert_eq!(vec![6, 4, 5], trx_ids(released));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn records_without_a_timestamp_follow_the_newest() {
+        let mut buffer = ReorderBuffer::new(0);
+
+        assert!(buffer.push(record(1, None)).is_ok());
+        assert!(buffer.push(record(2, Some(50))).is_ok());
+        assert!(buffer.push(record(3, None)).is_ok());
+
+        let mut released = Vec::new();
+        while let Some(record) = buffer.pop_ready() {
+            released.push(record);
+        }
+        assert_eq!(vec![1, 2, 3], trx_ids(released));
+    }
+}
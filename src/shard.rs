@@ -0,0 +1,724 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::mpsc::{channel, sync_channel, Receiver, SyncSender},
+    thread::JoinHandle,
+};
+
+use crate::{
+    account::positive_amount,
+    calculator::{disputed_transfer, Calculator, Transfer},
+    csvparser::Message,
+    error::{EngineError, TransactionError},
+    money::Money,
+    record::{ClientId, Record, RecordType, Timestamp, TransactionId},
+    rejects::Rejection,
+};
+
+// the engine of a worker thread and the records it refused
+struct Worker {
+    calculator: Calculator,
+    rejections: Vec<Rejection>,
+    // ids the router took for records this worker then refused, free to be used again
+    refused_ids: HashSet<TransactionId>,
+}
+
+impl Worker {
+    // applies `record`, a refusal is reported for the record as it was read; the router
+    // checked its transaction id already
+    fn apply(&mut self, record: &Record, read: &Record) -> Result<(), TransactionError> {
+        let result = self.calculator.calculate_unique(record);
+        if let Err(error) = result {
+            log::debug!("Worker::apply: record rejected, error == {}", error);
+            self.rejections.push(Rejection::refused(read, error));
+            if record.record_type.takes_id() {
+                self.refused_ids.insert(record.trx_id);
+            }
+        }
+        result
+    }
+
+    fn advance_to(&mut self, now: Option<Timestamp>) {
+        if let Some(now) = now {
+            self.calculator.advance_to(now);
+        }
+    }
+}
+
+type Task = Box<dyn FnOnce(&mut Worker) + Send>;
+
+// a record with the clock of the router when it was routed, which is the latest timestamp
+// of any record before it, as the clock of a single engine would be
+type Routed = (Option<Timestamp>, Record);
+
+enum Command {
+    Apply(Vec<Routed>),
+    Run(Task),
+}
+
+struct Shard {
+    sender: SyncSender<Command>,
+    handle: JoinHandle<Worker>,
+    // records routed to the worker but not sent yet
+    pending: Vec<Routed>,
+}
+
+/// Spreads records over worker threads by client id, each worker with its own `Calculator`, so
+/// the records of a client are applied in order while different clients run in parallel.
+///
+/// The router keeps what spans clients: transaction ids are checked for duplicates before a
+/// record reaches a worker, and a transfer between clients of different workers, or the
+/// chargeback of one, is applied step by step on both workers so it still applies fully or
+/// not at all.
+///
+/// Records reach a worker in batches of up to `batch_size` over a channel of `capacity`
+/// batches, a router that runs ahead of a worker waits for it. A batch goes out once it is
+/// full, before a step of a transfer on its worker, and at the end.
+///
+/// Every record reaches its worker with the clock of the router, the latest timestamp of any
+/// record so far, so disputes expire at the same records as with a single `Calculator`.
+pub struct ShardedCalculator {
+    shards: Vec<Shard>,
+    transaction_ids: HashSet<TransactionId>,
+    transfers: HashMap<TransactionId, Transfer>,
+    first_seen: Vec<ClientId>,
+    seen: HashSet<ClientId>,
+    latest: Option<Timestamp>,
+    rejections: Vec<Rejection>,
+    batch_size: usize,
+}
+
+impl ShardedCalculator {
+    /// Starts `workers` threads, at least one, each with an engine made by `engine` and a
+    /// channel holding up to `capacity` batches of up to `batch_size` records, at least one.
+    pub fn new(
+        workers: usize,
+        capacity: usize,
+        batch_size: usize,
+        engine: impl Fn() -> Calculator,
+    ) -> Self {
+        Self::start(
+            (0..workers.max(1)).map(|_| engine()).collect(),
+            capacity,
+            batch_size,
+        )
+    }
+
+    /// Starts `workers` threads, at least one, that carry on from `calculator`, e.g. one
+    /// restored from a snapshot. Its accounts are spread over the workers, and every worker
+    /// gets its policy, operators and rates. Channels and batches are sized as with `new`.
+    pub fn from_calculator(
+        workers: usize,
+        capacity: usize,
+        batch_size: usize,
+        calculator: Calculator,
+    ) -> Self {
+        let workers = workers.max(1);
+        let (calculators, first_seen, transaction_ids, transfers) =
+            calculator.split(workers, |client_id| shard_index(client_id, workers));
+        Self {
+            seen: first_seen.iter().copied().collect(),
+            first_seen,
+            transaction_ids,
+            transfers,
+            ..Self::start(calculators, capacity, batch_size)
+        }
+    }
+
+    fn start(calculators: Vec<Calculator>, capacity: usize, batch_size: usize) -> Self {
+        let log_header = "ShardedCalculator::start";
+        log::debug!("{}: starting {} workers", log_header, calculators.len());
+        let shards = calculators
+            .into_iter()
+            .map(|calculator| {
+                let (sender, receiver) = sync_channel::<Command>(capacity);
+                let worker = Worker {
+                    calculator,
+                    rejections: Vec::<Rejection>::new(),
+                    refused_ids: HashSet::<TransactionId>::new(),
+                };
+                let handle = std::thread::spawn(move || work(worker, receiver));
+                Shard {
+                    sender,
+                    handle,
+                    pending: Vec::<Routed>::new(),
+                }
+            })
+            .collect();
+
+        Self {
+            shards,
+            transaction_ids: HashSet::<TransactionId>::new(),
+            transfers: HashMap::<TransactionId, Transfer>::new(),
+            first_seen: Vec::<ClientId>::new(),
+            seen: HashSet::<ClientId>::new(),
+            latest: None,
+            rejections: Vec::<Rejection>::new(),
+            batch_size: batch_size.max(1),
+        }
+    }
+
+    /// Routes the records from `receiver` until `Message::Finished` arrives, then finishes, see
+    /// `finish`. A failed parser, or a sender that goes away before the end, is an error.
+    pub fn run(
+        mut self,
+        receiver: Receiver<Message>,
+    ) -> Result<(Calculator, Vec<Rejection>), EngineError> {
+        let log_header = "ShardedCalculator::run";
+        for message in receiver {
+            let batch = match message {
+                Message::Records(batch) => batch,
+                Message::Finished => {
+                    log::debug!("{}: Finished received", log_header);
+                    return self.finish();
+                }
+                Message::Failed(reason) => {
+                    log::debug!("{}: the parser failed, reason == {}", log_header, reason);
+                    return Err(EngineError::ParserFailed(reason));
+                }
+            };
+            for record in batch {
+                if let Err(error) = self.calculate(record) {
+                    return Err(self.diagnose(error));
+                }
+            }
+        }
+
+        log::debug!("{}: sender disconnected before the end", log_header);
+        Err(EngineError::ParserStopped)
+    }
+
+    /// Routes a single record to the worker of its client. Refused records are collected and
+    /// returned by `finish`; an error means a worker stopped.
+    pub fn calculate(&mut self, record: Record) -> Result<(), EngineError> {
+        let log_header = "ShardedCalculator::calculate";
+        self.see(record.client_id);
+        if let Some(to_client) = record
+            .to_client
+            .filter(|_| record.record_type == RecordType::Transfer)
+        {
+            self.see(to_client);
+        }
+        self.latest = self.latest.max(record.timestamp);
+
+        if record.record_type.takes_id()
+            && !self.transaction_ids.insert(record.trx_id)
+            && !self.release_refused_id(record.trx_id)?
+        {
+            log::debug!(
+                "{}: transaction id already used, record == {}",
+                log_header,
+                record
+            );
+            self.rejections.push(Rejection::refused(
+                &record,
+                TransactionError::DuplicateTransaction,
+            ));
+            return Ok(());
+        }
+
+        let shard = self.shard_of(record.client_id);
+        match (record.record_type, record.to_client) {
+            (RecordType::Transfer, Some(to_client))
+                if to_client != record.client_id && self.shard_of(to_client) != shard =>
+            {
+                self.transfer(record, to_client)
+            }
+            _ => match disputed_transfer(&self.transfers, &record) {
+                Some(transfer) => self.settle_transfer(record, transfer),
+                None => self.queue(shard, record),
+            },
+        }
+    }
+
+    /// Waits for every worker to apply its records and joins their engines into one. Returns
+    /// the engine and every refused record, in no particular order, or why a worker stopped.
+    pub fn finish(mut self) -> Result<(Calculator, Vec<Rejection>), EngineError> {
+        let log_header = "ShardedCalculator::finish";
+        if let Err(error) = self.flush_all() {
+            return Err(self.diagnose(error));
+        }
+        let latest = self.latest;
+        let mut rejections = self.rejections;
+        let mut calculators = Vec::<Calculator>::new();
+        for (index, shard) in self.shards.into_iter().enumerate() {
+            // a worker that stopped early has panicked, the join below says why
+            let _ = shard.sender.send(Command::Run(Box::new(move |worker| {
+                worker.advance_to(latest)
+            })));
+            drop(shard.sender);
+            let worker = join(index, shard.handle)?;
+            rejections.extend(worker.rejections);
+            for trx_id in &worker.refused_ids {
+                self.transaction_ids.remove(trx_id);
+            }
+            calculators.push(worker.calculator);
+        }
+
+        log::debug!("{}: merging {} shards", log_header, calculators.len());
+        let calculator = Calculator::merge(
+            calculators,
+            self.first_seen,
+            self.transaction_ids,
+            self.transfers,
+        );
+        Ok((calculator, rejections))
+    }
+
+    // a transfer between clients of different workers - the destination is checked, the source
+    // pays and only then the destination is paid, nothing else reaches the destination between
+    fn transfer(&mut self, record: Record, to_client: ClientId) -> Result<(), EngineError> {
+        let log_header = "ShardedCalculator::transfer";
+        let (from, to) = (self.shard_of(record.client_id), self.shard_of(to_client));
+        let source = record.client_id;
+        self.send(
+            from,
+            Command::Run(Box::new(move |worker| {
+                worker.calculator.open_account(source)
+            })),
+        )?;
+        if let Err(error) = positive_amount(&record) {
+            log::debug!("{}: transfer refused, error == {}", log_header, error);
+            self.transaction_ids.remove(&record.trx_id);
+            self.rejections.push(Rejection::refused(&record, error));
+            return Ok(());
+        }
+        let now = self.latest;
+        let checked = record.clone();
+        let mut result = self.ask(to, move |worker| {
+            worker.advance_to(now);
+            worker.calculator.check_transfer_in(&checked, to_client)
+        })?;
+        if result.is_ok() {
+            let paid = record.clone();
+            result = self.ask(from, move |worker| {
+                worker.advance_to(now);
+                worker.calculator.transfer_out(&paid)
+            })?;
+        }
+        if let Err(error) = result {
+            log::debug!("{}: transfer refused, error == {}", log_header, error);
+            self.transaction_ids.remove(&record.trx_id);
+            self.rejections.push(Rejection::refused(&record, error));
+            return Ok(());
+        }
+
+        self.transfers.insert(
+            record.trx_id,
+            Transfer {
+                from: record.client_id,
+                to: to_client,
+                currency: record.currency,
+            },
+        );
+        self.send(
+            to,
+            Command::Run(Box::new(move |worker| {
+                worker
+                    .calculator
+                    .transfer_in(&record, to_client)
+                    .expect("checked transfer was refused");
+            })),
+        )
+    }
+
+    // a dispute, resolve or chargeback of a transfer between clients of different workers acts
+    // on the destination, a chargeback also refunds the source once it is known to succeed
+    fn settle_transfer(&mut self, record: Record, transfer: Transfer) -> Result<(), EngineError> {
+        let log_header = "ShardedCalculator::settle_transfer";
+        let (from, to) = (self.shard_of(transfer.from), self.shard_of(transfer.to));
+        let rerouted = Record {
+            client_id: transfer.to,
+            ..record.clone()
+        };
+        let now = self.latest;
+        if record.record_type != RecordType::Chargeback {
+            return self.send(
+                to,
+                Command::Run(Box::new(move |worker| {
+                    worker.advance_to(now);
+                    let _ = worker.apply(&rerouted, &record);
+                })),
+            );
+        }
+
+        let settled = rerouted.clone();
+        let amount = self.ask(to, move |worker| {
+            worker.advance_to(now);
+            worker
+                .calculator
+                .settled_amount(&settled)
+                .unwrap_or(Money::ZERO)
+        })?;
+        if let Err(error) = self.ask(from, move |worker| {
+            worker.advance_to(now);
+            worker
+                .calculator
+                .check_refund(transfer.from, transfer.currency, amount)
+        })? {
+            log::debug!("{}: refund refused, error == {}", log_header, error);
+            self.rejections.push(Rejection::refused(&record, error));
+            return Ok(());
+        }
+
+        if self.ask(to, move |worker| worker.apply(&rerouted, &record).is_ok())? {
+            self.send(
+                from,
+                Command::Run(Box::new(move |worker| {
+                    worker
+                        .calculator
+                        .refund(transfer.from, transfer.currency, amount)
+                        .expect("checked refund was refused");
+                })),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn shard_of(&self, client_id: ClientId) -> usize {
+        shard_index(client_id, self.shards.len())
+    }
+
+    fn see(&mut self, client_id: ClientId) {
+        if self.seen.insert(client_id) {
+            self.first_seen.push(client_id);
+        }
+    }
+
+    fn queue(&mut self, shard: usize, record: Record) -> Result<(), EngineError> {
+        self.shards[shard].pending.push((self.latest, record));
+        if self.shards[shard].pending.len() >= self.batch_size {
+            self.flush(shard)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, index: usize) -> Result<(), EngineError> {
+        let shard = &mut self.shards[index];
+        if shard.pending.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::take(&mut shard.pending);
+        shard
+            .sender
+            .send(Command::Apply(batch))
+            .map_err(|_| EngineError::WorkerStopped(index))
+    }
+
+    fn flush_all(&mut self) -> Result<(), EngineError> {
+        for shard in 0..self.shards.len() {
+            self.flush(shard)?;
+        }
+        Ok(())
+    }
+
+    // whether the record that took `trx_id` was refused by its worker, which gives the id up;
+    // waits for every worker, so it is only asked when an id comes again
+    fn release_refused_id(&mut self, trx_id: TransactionId) -> Result<bool, EngineError> {
+        let mut released = false;
+        for shard in 0..self.shards.len() {
+            released |= self.ask(shard, move |worker| worker.refused_ids.remove(&trx_id))?;
+        }
+        Ok(released)
+    }
+
+    // sends a task after the records routed to the worker before it
+    fn send(&mut self, shard: usize, command: Command) -> Result<(), EngineError> {
+        self.flush(shard)?;
+        self.shards[shard]
+            .sender
+            .send(command)
+            .map_err(|_| EngineError::WorkerStopped(shard))
+    }
+
+    // runs `task` on a worker after everything routed to it before, and waits for the result
+    fn ask<T: Send + 'static>(
+        &mut self,
+        shard: usize,
+        task: impl FnOnce(&mut Worker) -> T + Send + 'static,
+    ) -> Result<T, EngineError> {
+        let (reply, result) = channel::<T>();
+        self.send(
+            shard,
+            Command::Run(Box::new(move |worker| {
+                let _ = reply.send(task(worker));
+            })),
+        )?;
+        result.recv().map_err(|_| EngineError::WorkerStopped(shard))
+    }
+
+    // stops every worker and finds out why the worker named by `error` stopped
+    fn diagnose(self, error: EngineError) -> EngineError {
+        let log_header = "ShardedCalculator::diagnose";
+        log::debug!("{}: stopping every worker, error == {}", log_header, error);
+        let EngineError::WorkerStopped(index) = error else {
+            return error;
+        };
+        match self.shards.into_iter().nth(index) {
+            Some(shard) => {
+                drop(shard.sender);
+                join(index, shard.handle).err().unwrap_or(error)
+            }
+            None => error,
+        }
+    }
+}
+
+// waits for a worker to finish, a panic becomes an error with the panic message
+fn join(index: usize, handle: JoinHandle<Worker>) -> Result<Worker, EngineError> {
+    handle.join().map_err(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| String::from("unknown panic"));
+        EngineError::WorkerPanicked(index, message)
+    })
+}
+
+// the worker out of `shards` that applies the records of a client
+fn shard_index(client_id: ClientId, shards: usize) -> usize {
+    client_id as usize % shards
+}
+
+fn work(mut worker: Worker, receiver: Receiver<Command>) -> Worker {
+    for command in receiver {
+        match command {
+            Command::Apply(batch) => {
+                for (now, record) in &batch {
+                    worker.advance_to(*now);
+                    let _ = worker.apply(record, record);
+                }
+            }
+            Command::Run(task) => task(&mut worker),
+        }
+    }
+    worker
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        calculator::SortOrder,
+        policy::{Policy, SECONDS_PER_DAY},
+    };
+
+    fn record(
+        record_type: RecordType,
+        client_id: ClientId,
+        trx_id: TransactionId,
+        amount: Option<&str>,
+        to_client: Option<ClientId>,
+    ) -> Record {
+        Record {
+            amount: amount.map(|amount| amount.parse().unwrap()),
+            to_client,
+            ..Record::new(record_type, client_id, trx_id)
+        }
+    }
+
+    fn records() -> Vec<Record> {
+        use RecordType::*;
+        vec![
+            record(Deposit, 5, 1, Some("10"), None),
+            record(Deposit, 2, 2, Some("3"), None),
+            record(Deposit, 1, 3, Some("7"), None),
+            record(Deposit, 4, 4, Some("1"), None),
+            // transfers within and across shards, one without funds and one negative
+            record(Transfer, 5, 5, Some("4"), Some(1)),
+            record(Transfer, 2, 6, Some("1"), Some(5)),
+            record(Transfer, 2, 7, Some("5"), Some(1)),
+            // the id of the refused transfer is free again
+            record(Transfer, 4, 7, Some("1"), Some(1)),
+            record(Transfer, 1, 10, Some("-2"), Some(2)),
+            // a dispute named by the source, charged back at the destination and refunded
+            record(Dispute, 5, 5, None, None),
+            record(Chargeback, 1, 5, None, None),
+            // the destination is locked now
+            record(Transfer, 5, 8, Some("1"), Some(1)),
+            record(Dispute, 2, 6, None, None),
+            record(Resolve, 5, 6, None, None),
+            // refused by its worker and sent again, then a duplicate of another worker
+            record(Withdrawal, 4, 9, Some("2"), None),
+            record(Deposit, 4, 9, Some("2"), None),
+            record(Deposit, 5, 9, Some("1"), None),
+        ]
+        .into_iter()
+        .zip(1..)
+        .map(|(record, line)| Record { line, ..record })
+        .collect()
+    }
+
+    fn sorted(mut rejections: Vec<Rejection>) -> Vec<Rejection> {
+        rejections.sort_by_key(|rejection| rejection.line);
+        rejections
+    }
+
+    // applies `records` on a single engine and on 1 and 3 workers, checks that the results are
+    // the same and returns the refused records
+    fn snapshot_of(calculator: &Calculator) -> String {
+        let mut snapshot = Vec::new();
+        calculator.write_snapshot(&mut snapshot).unwrap();
+        String::from_utf8(snapshot).unwrap()
+    }
+
+    fn assert_sharded_matches_single(policy: Policy, records: Vec<Record>) -> Vec<Rejection> {
+        let mut single = Calculator::with_policy(policy);
+        let mut expected_rejections = Vec::<Rejection>::new();
+        for record in &records {
+            if let Err(error) = single.calculate(record) {
+                expected_rejections.push(Rejection::refused(record, error));
+            }
+        }
+
+        for workers in [1, 3] {
+            let mut sharded =
+                ShardedCalculator::new(workers, 1, 2, || Calculator::with_policy(policy));
+            for record in records.clone() {
+                assert_eq!(Ok(()), sharded.calculate(record));
+            }
+            let (calculator, rejections) = sharded.finish().unwrap();
+
+            for order in [SortOrder::ClientId, SortOrder::FirstSeen] {
+                assert_eq!(
+                    single.sorted_accounts(order),
+                    calculator.sorted_accounts(order)
+                );
+            }
+            // the snapshot also holds the transaction ids taken, refused records take none
+            assert_eq!(snapshot_of(&single), snapshot_of(&calculator));
+            assert_eq!(sorted(expected_rejections.clone()), sorted(rejections));
+        }
+        expected_rejections
+    }
+
+    #[test]
+    fn sharded_matches_a_single_calculator() {
+        let rejections = assert_sharded_matches_single(Policy::default(), records());
+        assert_eq!(5, rejections.len());
+    }
+
+    #[test]
+    fn sharded_keeps_the_source_of_a_refused_transfer_like_a_single_calculator() {
+        use RecordType::*;
+        let rejections = assert_sharded_matches_single(
+            Policy::default(),
+            vec![
+                // the only record of client 3, refused before either account is touched
+                record(Transfer, 3, 1, Some("-1"), Some(2)),
+                record(Deposit, 2, 2, Some("1"), None),
+                record(Dispute, 2, 2, None, None),
+                record(Chargeback, 2, 2, None, None),
+                // refused by the locked destination, the only record of client 4
+                record(Transfer, 4, 3, Some("1"), Some(2)),
+            ],
+        );
+        assert_eq!(2, rejections.len());
+    }
+
+    #[test]
+    fn sharded_expires_disputes_like_a_single_calculator() {
+        use RecordType::*;
+        let at = |record: Record, timestamp| Record {
+            timestamp: Some(timestamp),
+            ..record
+        };
+        let records: Vec<Record> = vec![
+            at(record(Deposit, 1, 1, Some("10"), None), 0),
+            at(record(Deposit, 2, 2, Some("10"), None), 0),
+            at(record(Deposit, 4, 3, Some("5"), None), 0),
+            at(record(Dispute, 1, 1, None, None), 10),
+            at(record(Dispute, 4, 3, None, None), 20),
+            // expires both disputes, before the records of clients 1 and 4 that follow
+            at(record(Deposit, 2, 4, Some("1"), None), 1_000_000),
+            record(Chargeback, 1, 1, None, None),
+            record(Transfer, 4, 5, Some("5"), Some(2)),
+        ]
+        .into_iter()
+        .zip(1..)
+        .map(|(record, line)| Record { line, ..record })
+        .collect();
+
+        let rejections = assert_sharded_matches_single(
+            Policy {
+                hold_period: Some(SECONDS_PER_DAY),
+                ..Policy::default()
+            },
+            records,
+        );
+        assert_eq!(1, rejections.len());
+        assert_eq!(7, rejections[0].line);
+    }
+
+    #[test]
+    fn sharded_carries_on_from_a_restored_calculator() {
+        let mut single = Calculator::new();
+        let mut expected_rejections = Vec::<Rejection>::new();
+        let mut snapshot = Vec::<u8>::new();
+        for record in records() {
+            // the first day ends after the first transfers
+            if record.line == 7 {
+                single.write_snapshot(&mut snapshot).unwrap();
+            }
+            if let Err(error) = single.calculate(&record) {
+                if record.line >= 7 {
+                    expected_rejections.push(Rejection::refused(&record, error));
+                }
+            }
+        }
+
+        for workers in [1, 3] {
+            let mut restored = Calculator::new();
+            restored.restore_snapshot(&snapshot[..]).unwrap();
+            let mut sharded = ShardedCalculator::from_calculator(workers, 1, 2, restored);
+            for record in records().into_iter().filter(|record| record.line >= 7) {
+                assert_eq!(Ok(()), sharded.calculate(record));
+            }
+            let (calculator, rejections) = sharded.finish().unwrap();
+
+            for order in [SortOrder::ClientId, SortOrder::FirstSeen] {
+                assert_eq!(
+                    single.sorted_accounts(order),
+                    calculator.sorted_accounts(order)
+                );
+            }
+            // the snapshot also holds the transaction ids taken, refused records take none
+            assert_eq!(snapshot_of(&single), snapshot_of(&calculator));
+            assert_eq!(sorted(expected_rejections.clone()), sorted(rejections));
+        }
+    }
+
+    #[test]
+    fn sharded_reports_a_panicked_worker_and_a_stopped_or_failed_parser() {
+        let mut sharded = ShardedCalculator::new(2, 1, 2, Calculator::new);
+        let panic: Task = Box::new(|_| panic!("boom"));
+        assert_eq!(Ok(()), sharded.send(0, Command::Run(panic)));
+        for record in records() {
+            if sharded.calculate(record).is_err() {
+                break;
+            }
+        }
+        assert_eq!(
+            Err(EngineError::WorkerPanicked(0, String::from("boom"))),
+            sharded.finish().map(|_| ())
+        );
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(1);
+        sender.send(Message::Records(records())).unwrap();
+        drop(sender);
+        let sharded = ShardedCalculator::new(2, 1, 2, Calculator::new);
+        assert_eq!(
+            Err(EngineError::ParserStopped),
+            sharded.run(receiver).map(|_| ())
+        );
+
+        let (sender, receiver) = std::sync::mpsc::sync_channel(2);
+        sender.send(Message::Records(records())).unwrap();
+        sender.send(Message::Failed(String::from("gone"))).unwrap();
+        let sharded = ShardedCalculator::new(2, 1, 2, Calculator::new);
+        assert_eq!(
+            Err(EngineError::ParserFailed(String::from("gone"))),
+            sharded.run(receiver).map(|_| ())
+        );
+    }
+}
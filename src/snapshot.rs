@@ -0,0 +1,98 @@
+use std::io::{self, Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    account::AccountState,
+    calculator::Transfer,
+    record::{ClientId, TransactionId},
+};
+
+/// Version of the snapshot format written by `Calculator::write_snapshot`, a snapshot of any
+/// other version is refused.
+pub const SNAPSHOT_VERSION: u64 = 1;
+
+/// Why a snapshot could not be written or restored.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The snapshot file could not be opened or created.
+    Io(io::Error),
+    /// The snapshot could not be read or written, or is not json of the expected shape.
+    Json(serde_json::Error),
+    /// The snapshot has another format version, `None` when it has none.
+    Version(Option<u64>),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(error) => write!(f, "{}", error),
+            SnapshotError::Json(error) => write!(f, "{}", error),
+            SnapshotError::Version(Some(version)) => write!(
+                f,
+                "snapshot version {} is not supported, expected {}",
+                version, SNAPSHOT_VERSION
+            ),
+            SnapshotError::Version(None) => write!(f, "snapshot has no version"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        SnapshotError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for SnapshotError {
+    fn from(error: serde_json::Error) -> Self {
+        SnapshotError::Json(error)
+    }
+}
+
+// the first value of a snapshot, read before the state so a snapshot of another version is
+// reported as such instead of as a field that does not fit
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: Option<u64>,
+}
+
+// the part of an engine that outlives a run, as written to a snapshot after the header
+#[derive(Serialize, Deserialize)]
+pub(crate) struct EngineState {
+    // sorted by client id
+    pub(crate) accounts: Vec<AccountState>,
+    pub(crate) first_seen: Vec<ClientId>,
+    // sorted, like the transfers
+    pub(crate) transaction_ids: Vec<TransactionId>,
+    pub(crate) transfers: Vec<(TransactionId, Transfer)>,
+}
+
+impl EngineState {
+    pub(crate) fn write(&self, mut writer: impl Write) -> Result<(), SnapshotError> {
+        let header = Header {
+            version: Some(SNAPSHOT_VERSION),
+        };
+        serde_json::to_writer(&mut writer, &header)?;
+        writer.write_all(b"\n")?;
+        serde_json::to_writer(&mut writer, self)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    // the state is read straight from `reader`, never held as a json tree as well
+    pub(crate) fn read(reader: impl Read) -> Result<Self, SnapshotError> {
+        let log_header = "EngineState::read";
+        let mut deserializer = serde_json::Deserializer::from_reader(reader);
+        let header = Header::deserialize(&mut deserializer)?;
+        log::debug!("{}: snapshot version == {:?}", log_header, header.version);
+        if header.version != Some(SNAPSHOT_VERSION) {
+            return Err(SnapshotError::Version(header.version));
+        }
+        let state = EngineState::deserialize(&mut deserializer)?;
+        deserializer.end()?;
+        Ok(state)
+    }
+}
@@ -0,0 +1,40 @@
+use std::{
+    fs,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+#[test]
+fn reads_stdin_in_the_place_of_dash_among_the_inputs() {
+    let second = std::env::temp_dir().join(format!(
+        "transactioner-{}-after-stdin.csv",
+        std::process::id()
+    ));
+    fs::write(&second, "type,client,tx,amount\nwithdrawal,1,2,4\n").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_transactioner"))
+        .arg("-")
+        .arg(&second)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"type,client,tx,amount\ndeposit,1,1,10\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    fs::remove_file(&second).unwrap();
+
+    assert!(output.status.success());
+    let summary = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        Some("1,,6.0000,0.0000,6.0000,false,0.0000,false"),
+        summary.lines().nth(1),
+        "{}",
+        summary
+    );
+}